@@ -0,0 +1,157 @@
+/// What ADD, SUB, MUL, DIV, INC and SHL do when the exact result does not fit the register.
+/// In every mode the overflow flag records whether the last of them overflowed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OverflowMode {
+    Wrapping,   // Keep the low bits of the result, two's complement
+    Trapping,   // Stop with `VmError::Overflow`, leaving the register untouched
+    Saturating, // Clamp to the largest or smallest value of the register's width
+}
+
+/// Every possible answer for one operation, `OverflowMode` picks between them
+pub struct Outcome<T> {
+    pub wrapped: T,
+    pub saturated: T,
+    pub overflow: bool,
+}
+
+/// Arithmetic shared by the three register widths with explicit overflow behaviour
+pub trait Word: Copy {
+    fn overflow_add(self, rhs: Self) -> Outcome<Self>;
+    fn overflow_sub(self, rhs: Self) -> Outcome<Self>;
+    fn overflow_mul(self, rhs: Self) -> Outcome<Self>;
+    // Only MIN / -1 overflows, a zero divisor is the caller's to reject
+    fn overflow_div(self, rhs: Self) -> Outcome<Self>;
+    // Bits shifted past the top are lost, shifting by the width or more leaves zero
+    fn overflow_shl(self, shift: u8) -> Outcome<Self>;
+    // Arithmetic shift, shifting by the width or more leaves only the sign
+    fn shift_right(self, shift: u8) -> Self;
+}
+
+macro_rules! impl_word {
+    ($t:ty) => {
+        impl Word for $t {
+            fn overflow_add(self, rhs: $t) -> Outcome<$t> {
+                let (wrapped, overflow) = self.overflowing_add(rhs);
+                Outcome {
+                    wrapped,
+                    saturated: self.saturating_add(rhs),
+                    overflow,
+                }
+            }
+
+            fn overflow_sub(self, rhs: $t) -> Outcome<$t> {
+                let (wrapped, overflow) = self.overflowing_sub(rhs);
+                Outcome {
+                    wrapped,
+                    saturated: self.saturating_sub(rhs),
+                    overflow,
+                }
+            }
+
+            fn overflow_mul(self, rhs: $t) -> Outcome<$t> {
+                let (wrapped, overflow) = self.overflowing_mul(rhs);
+                Outcome {
+                    wrapped,
+                    saturated: self.saturating_mul(rhs),
+                    overflow,
+                }
+            }
+
+            fn overflow_div(self, rhs: $t) -> Outcome<$t> {
+                let (wrapped, overflow) = self.overflowing_div(rhs);
+                Outcome {
+                    wrapped,
+                    saturated: if overflow { <$t>::MAX } else { wrapped },
+                    overflow,
+                }
+            }
+
+            fn overflow_shl(self, shift: u8) -> Outcome<$t> {
+                let (wrapped, overflow) = if u32::from(shift) >= <$t>::BITS {
+                    (0, self != 0)
+                } else {
+                    let wrapped = self << shift;
+                    (wrapped, wrapped >> shift != self)
+                };
+                Outcome {
+                    wrapped,
+                    saturated: if !overflow {
+                        wrapped
+                    } else if self < 0 {
+                        <$t>::MIN
+                    } else {
+                        <$t>::MAX
+                    },
+                    overflow,
+                }
+            }
+
+            fn shift_right(self, shift: u8) -> $t {
+                self >> u32::from(shift).min(<$t>::BITS - 1)
+            }
+        }
+    };
+}
+
+impl_word!(i32);
+impl_word!(i64);
+impl_word!(i128);
+
+impl<T> Outcome<T> {
+    /// The value to store under `mode`, or `None` if the VM should trap
+    pub fn resolve(self, mode: OverflowMode) -> Option<T> {
+        if !self.overflow {
+            return Some(self.wrapped);
+        }
+        match mode {
+            OverflowMode::Wrapping => Some(self.wrapped),
+            OverflowMode::Trapping => None,
+            OverflowMode::Saturating => Some(self.saturated),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_modes() {
+        assert_eq!(i32::MAX.overflow_add(1).resolve(OverflowMode::Wrapping), Some(i32::MIN));
+        assert_eq!(i32::MAX.overflow_add(1).resolve(OverflowMode::Saturating), Some(i32::MAX));
+        assert_eq!(i32::MAX.overflow_add(1).resolve(OverflowMode::Trapping), None);
+        assert_eq!(i32::MAX.overflow_add(-1).resolve(OverflowMode::Trapping), Some(i32::MAX - 1));
+    }
+
+    #[test]
+    fn test_mul_modes() {
+        assert_eq!(i64::MIN.overflow_mul(-1).resolve(OverflowMode::Wrapping), Some(i64::MIN));
+        assert_eq!(i64::MIN.overflow_mul(-1).resolve(OverflowMode::Saturating), Some(i64::MAX));
+        assert_eq!(i128::MIN.overflow_mul(2).resolve(OverflowMode::Saturating), Some(i128::MIN));
+    }
+
+    #[test]
+    fn test_div_modes() {
+        assert_eq!(i32::MIN.overflow_div(-1).resolve(OverflowMode::Wrapping), Some(i32::MIN));
+        assert_eq!(i32::MIN.overflow_div(-1).resolve(OverflowMode::Saturating), Some(i32::MAX));
+        assert_eq!(i32::MIN.overflow_div(-1).resolve(OverflowMode::Trapping), None);
+        assert_eq!((-7i32).overflow_div(2).resolve(OverflowMode::Trapping), Some(-3));
+    }
+
+    #[test]
+    fn test_shl() {
+        assert!(!(-1i32).overflow_shl(3).overflow);
+        assert!(0x4000_0000i32.overflow_shl(1).overflow);
+        assert_eq!(0x4000_0000i32.overflow_shl(1).saturated, i32::MAX);
+        assert_eq!(1i64.overflow_shl(64).wrapped, 0);
+        assert!(1i64.overflow_shl(64).overflow);
+        assert!(!0i128.overflow_shl(200).overflow);
+    }
+
+    #[test]
+    fn test_shift_right() {
+        assert_eq!((-8i32).shift_right(1), -4);
+        assert_eq!((-8i32).shift_right(40), -1);
+        assert_eq!(8i64.shift_right(255), 0);
+    }
+}
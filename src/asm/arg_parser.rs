@@ -1,16 +1,94 @@
-use nom::digit;
+use nom::{alphanumeric, digit};
 use nom::types::CompleteStr;
 use asm::Token;
 
-/// Parser for integer numbers, which we preface with `#` in our assembly language:
-/// #100
-named!(pub i32_arg<CompleteStr, Token>,
+/// Parser for immediate operands. Literals are decimal, hex or binary with an optional sign,
+/// and may span the full 128-bit range; the encoded width comes from the destination register:
+/// 100
+/// -6
+/// 0xFF
+/// 0b1010
+/// The original `i32` prefix is still accepted before a plain decimal:
+/// i32100
+named!(pub imm_arg<CompleteStr, Token>,
     ws!(
         do_parse!(
-            tag!("i32") >>
-            val: digit >>
+            val: alt!(
+                map_opt!(preceded!(tag!("i32"), alphanumeric), |s: CompleteStr| decimal(&s)) |
+                map_opt!(recognize!(pair!(opt!(one_of!("+-")), alphanumeric)), |s: CompleteStr| parse_literal(&s))
+            ) >>
             (
-                Token::Number{value: val.parse::<i32>().unwrap()}
+                Token::Number{value: val}
+            )
+        )
+    )
+);
+
+// Digits only, as the original `i32` syntax allowed
+fn decimal(s: &str) -> Option<i128> {
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        parse_literal(s)
+    } else {
+        None
+    }
+}
+
+/// Parses an integer literal. Unsigned literals above `i128::MAX` are kept as their
+/// two's complement bit pattern so 128-bit constants can be written in full.
+pub fn parse_literal(s: &str) -> Option<i128> {
+    let (negative, s) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let magnitude = if s.starts_with("0x") || s.starts_with("0X") {
+        u128::from_str_radix(&s[2..], 16)
+    } else if s.starts_with("0b") || s.starts_with("0B") {
+        u128::from_str_radix(&s[2..], 2)
+    } else {
+        s.parse::<u128>()
+    }.ok()?;
+    if !negative {
+        Some(magnitude as i128)
+    } else if magnitude <= i128::MIN.unsigned_abs() {
+        Some((magnitude as i128).wrapping_neg())
+    } else {
+        None
+    }
+}
+
+/// Parser for raw byte operands, the shift amount of SHR/SHL/SHRU and the CAL index:
+/// shl r0 4
+/// cal 1
+named!(pub byte_arg<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            val: map_res!(digit, |s: CompleteStr| s.parse::<u8>()) >>
+            (
+                Token::Byte{value: val}
+            )
+        )
+    )
+);
+
+/// Parser for jump targets. A bare number is an absolute offset into the script,
+/// a signed one is relative to the jump instruction itself:
+/// jmp 12
+/// jne -6
+named!(pub target_arg<CompleteStr, Token>,
+    ws!(
+        alt!(
+            do_parse!(
+                val: map_res!(recognize!(pair!(one_of!("+-"), digit)), |s: CompleteStr| s.parse::<i32>()) >>
+                (
+                    Token::Target{relative: true, offset: val}
+                )
+            ) |
+            do_parse!(
+                val: map_res!(digit, |s: CompleteStr| s.parse::<i32>()) >>
+                (
+                    Token::Target{relative: false, offset: val}
+                )
             )
         )
     )
@@ -23,14 +101,72 @@ mod tests {
     #[test]
     fn test_parse_i32_arg() {
         // Test a valid integer operand
-        let result = i32_arg(CompleteStr("i3210"));
+        let result = imm_arg(CompleteStr("i3210"));
+        assert_eq!(result.is_ok(), true);
+        let (rest, value) = result.unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(value, Token::Number { value: 10 });
+
+        // The prefix is optional now, but only plain decimals may follow it
+        assert_eq!(imm_arg(CompleteStr("10")), Ok((CompleteStr(""), value)));
+        let result = imm_arg(CompleteStr("i32-5"));
+        assert_eq!(result.is_ok(), false);
+        let result = imm_arg(CompleteStr("i320x10"));
+        assert_eq!(result.is_ok(), false);
+    }
+
+    #[test]
+    fn test_parse_imm_arg() {
+        // Test a valid integer operand
+        let result = imm_arg(CompleteStr("10"));
         assert_eq!(result.is_ok(), true);
         let (rest, value) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(value, Token::Number { value: 10 });
 
-        // Test an invalid one (missing the #)
-        let result = i32_arg(CompleteStr("10"));
+        // Test invalid ones
+        let result = imm_arg(CompleteStr("r0"));
         assert_eq!(result.is_ok(), false);
+        assert!(imm_arg(CompleteStr("0xZZ")).is_err());
+        assert!(imm_arg(CompleteStr("12ab")).is_err());
+    }
+
+    #[test]
+    fn test_parse_literal() {
+        assert_eq!(parse_literal("-6"), Some(-6));
+        assert_eq!(parse_literal("+6"), Some(6));
+        assert_eq!(parse_literal("0xff"), Some(255));
+        assert_eq!(parse_literal("-0x10"), Some(-16));
+        assert_eq!(parse_literal("0b1010"), Some(10));
+        assert_eq!(parse_literal("-170141183460469231731687303715884105728"), Some(i128::MIN));
+        assert_eq!(parse_literal("-170141183460469231731687303715884105729"), None);
+        assert_eq!(parse_literal("0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"), Some(-1));
+        assert_eq!(parse_literal("0x100000000000000000000000000000000"), None);
+        assert_eq!(parse_literal("0x"), None);
+        assert_eq!(parse_literal(""), None);
+    }
+
+    #[test]
+    fn test_parse_byte_arg() {
+        let result = byte_arg(CompleteStr("255"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Byte { value: 255 })));
+
+        let result = byte_arg(CompleteStr("256"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_target_arg() {
+        let result = target_arg(CompleteStr("12"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Target { relative: false, offset: 12 })));
+
+        let result = target_arg(CompleteStr("-6"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Target { relative: true, offset: -6 })));
+
+        let result = target_arg(CompleteStr("+6"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Target { relative: true, offset: 6 })));
+
+        let result = target_arg(CompleteStr("r0"));
+        assert!(result.is_err());
     }
 } /*  */
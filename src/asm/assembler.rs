@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bytes::Bytes;
+use nom::types::CompleteStr;
+use nom::{Context, Err, ErrorKind};
+
+use asm::script_parser::{self, Located, Script, Statement};
+use asm::{offset, EncodeError};
+use asm::{ERR_BYTE, ERR_CALL, ERR_CONSTANT_NAME, ERR_CONSTANT_VALUE, ERR_DIRECTIVE};
+use asm::{ERR_END_OF_LINE, ERR_FILE_NAME, ERR_IMMEDIATE, ERR_MNEMONIC, ERR_REGISTER};
+use asm::{ERR_REGISTER_INDEX, ERR_REGISTER_WIDTH, ERR_SCRIPT_NAME, ERR_TARGET};
+use vm_script::REGSIZE;
+
+/// A located assembler diagnostic. `line` and `column` are 1-based,
+/// `source` is the offending line as written.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub expected: String,
+    pub found: String,
+    pub source: String,
+}
+
+impl AsmError {
+    /// The source line with a caret under the error column
+    pub fn snippet(&self) -> String {
+        let gutter = self.line.to_string().len();
+        // Keep tabs so the caret lines up with the source as displayed
+        let indent: String = self
+            .source
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "{:g$} |\n{} | {}\n{:g$} | {}^",
+            "",
+            self.line,
+            self.source,
+            "",
+            indent,
+            g = gutter
+        )
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}:{}:{}: expected {}, found {}",
+            self.file, self.line, self.column, self.expected, self.found
+        )?;
+        write!(f, "{}", self.snippet())
+    }
+}
+
+impl Error for AsmError {}
+
+/// Deepest `.include` nesting accepted, which also stops include cycles
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Assembles a source file into the script slice `VM::new` expects.
+/// Instructions before the first `.script name` header form an unnamed entry script.
+/// Every error in the file is reported, not just the first.
+pub fn assemble(file: &str, source: &str) -> Result<Vec<Bytes>, Vec<AsmError>> {
+    assemble_with(file, source, &mut |path: &str| fs::read_to_string(path))
+}
+
+/// Like `assemble`, reading `.include`d files through `load`. Include paths are
+/// resolved against the directory of the including file before `load` sees them.
+pub fn assemble_with(
+    file: &str,
+    source: &str,
+    load: &mut dyn FnMut(&str) -> io::Result<String>,
+) -> Result<Vec<Bytes>, Vec<AsmError>> {
+    let mut asm = Assembler {
+        file: file.to_string(),
+        lines: vec![],
+        origins: vec![],
+        sections: vec![],
+        constants: HashMap::new(),
+        errors: vec![],
+    };
+    asm.splice(0, file, source, 0);
+    // Included files are spliced in after their `.include`, so the length grows as we go
+    let mut line = 1;
+    while line <= asm.lines.len() {
+        asm.line(line, load);
+        line += 1;
+    }
+    asm.finish()
+}
+
+struct Section {
+    // Name, line and column of the `.script` header, if there is one
+    name: Option<(String, usize, usize)>,
+    script: Script,
+}
+
+// Where a line of the combined source came from
+struct Origin {
+    file: String,
+    line: usize,
+    depth: usize,
+}
+
+// Drives `script_parser::line` over a file and the files it includes, recovering at the next
+// line from every error. Lines are numbered by their 1-based position in the combined source,
+// with every include spliced in; `origins` maps them back to a file and line.
+struct Assembler {
+    file: String,
+    lines: Vec<String>,
+    origins: Vec<Origin>,
+    sections: Vec<Section>,
+    // `.equ` values and the line defining them
+    constants: HashMap<String, (i128, usize)>,
+    errors: Vec<(usize, AsmError)>,
+}
+
+impl Assembler {
+    fn splice(&mut self, at: usize, file: &str, source: &str, depth: usize) {
+        let count = source.lines().count();
+        self.lines
+            .splice(at..at, source.lines().map(|l| l.to_string()));
+        self.origins.splice(
+            at..at,
+            (1..=count).map(|line| Origin {
+                file: file.to_string(),
+                line,
+                depth,
+            }),
+        );
+    }
+
+    fn error(&mut self, line: usize, column: usize, expected: &str, found: String) {
+        let (file, source_line) = match self.origins.get(line - 1) {
+            Some(o) => (o.file.clone(), o.line),
+            None => (self.file.clone(), line),
+        };
+        let error = AsmError {
+            file,
+            line: source_line,
+            column,
+            expected: expected.to_string(),
+            found,
+            source: self.lines.get(line - 1).cloned().unwrap_or_default(),
+        };
+        self.errors.push((line, error));
+    }
+
+    // How to refer to `first` from a diagnostic on `line`
+    fn place(&self, first: usize, line: usize) -> String {
+        let (first, line) = (&self.origins[first - 1], &self.origins[line - 1]);
+        if first.file == line.file {
+            format!("line {}", first.line)
+        } else {
+            format!("{}:{}", first.file, first.line)
+        }
+    }
+
+    // 1-based column of the byte offset `at` of a line
+    fn column(&self, line: usize, at: usize) -> usize {
+        self.lines[line - 1][..at].chars().count() + 1
+    }
+
+    fn expected(&mut self, line: usize, at: usize, expected: &str) {
+        let column = self.column(line, at);
+        let found = found(&self.lines[line - 1][at..]);
+        self.error(line, column, expected, found);
+    }
+
+    fn current(&mut self) -> &mut Section {
+        if self.sections.is_empty() {
+            self.sections.push(Section {
+                name: None,
+                script: Script::default(),
+            });
+        }
+        self.sections.last_mut().unwrap()
+    }
+
+    fn line(&mut self, line: usize, load: &mut dyn FnMut(&str) -> io::Result<String>) {
+        let text = self.lines[line - 1].clone();
+        let input = CompleteStr(&text[..]);
+        match script_parser::line(input) {
+            Ok((_, statements)) => {
+                for statement in statements {
+                    self.statement(line, statement, load);
+                }
+            }
+            Err(Err::Failure(Context::Code(rest, ErrorKind::Custom(code)))) => {
+                self.expected(line, offset(input, rest), &describe(code))
+            }
+            Err(_) => self.expected(line, 0, "statement"),
+        }
+    }
+
+    fn statement(
+        &mut self,
+        line: usize,
+        statement: Statement,
+        load: &mut dyn FnMut(&str) -> io::Result<String>,
+    ) {
+        match statement {
+            Statement::Script { name, at } => {
+                let column = self.column(line, at);
+                self.sections.push(Section {
+                    name: Some((name, line, column)),
+                    script: Script::default(),
+                });
+            }
+            Statement::Equ { name, value, at } => self.equ(line, name, value, at),
+            Statement::Include { path, at } => self.include(line, &path, at, load),
+            statement => self.current().script.push(line, statement),
+        }
+    }
+
+    fn equ(&mut self, line: usize, name: String, value: i128, at: usize) {
+        if let Some(&(_, first)) = self.constants.get(&name) {
+            let found = format!("`{}`, first defined on {}", name, self.place(first, line));
+            let column = self.column(line, at);
+            return self.error(line, column, "unique constant", found);
+        }
+        self.constants.insert(name, (value, line));
+    }
+
+    // Splices `path`, relative to the including file, in after `line`
+    fn include(
+        &mut self,
+        line: usize,
+        path: &str,
+        at: usize,
+        load: &mut dyn FnMut(&str) -> io::Result<String>,
+    ) {
+        let (file, depth) = {
+            let origin = &self.origins[line - 1];
+            let dir = Path::new(&origin.file)
+                .parent()
+                .unwrap_or_else(|| Path::new(""));
+            let path = dir.join(path);
+            (path.to_string_lossy().into_owned(), origin.depth + 1)
+        };
+        if depth > MAX_INCLUDE_DEPTH {
+            let expected = format!("includes nested at most {} deep", MAX_INCLUDE_DEPTH);
+            return self.expected(line, at, &expected);
+        }
+        match load(&file) {
+            Ok(source) => self.splice(line, &file, &source, depth),
+            Err(e) => {
+                let column = self.column(line, at);
+                self.error(line, column, "readable file", format!("`{}` ({})", file, e));
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<Bytes>, Vec<AsmError>> {
+        let sections = ::std::mem::take(&mut self.sections);
+        if sections.is_empty() && self.errors.is_empty() {
+            let line = self.lines.len().max(1);
+            self.error(line, 1, "instruction", "end of file".to_string());
+        }
+
+        let mut scripts: HashMap<String, usize> = HashMap::new();
+        for (index, section) in sections.iter().enumerate() {
+            if let Some((ref name, line, column)) = section.name {
+                match scripts.get(name) {
+                    Some(&first) => {
+                        let first_line = sections[first].name.as_ref().unwrap().1;
+                        let found = format!(
+                            "`{}`, first defined on {}",
+                            name,
+                            self.place(first_line, line)
+                        );
+                        self.error(line, column, "unique script name", found);
+                    }
+                    None => {
+                        scripts.insert(name.clone(), index);
+                    }
+                }
+            }
+        }
+
+        let constants: HashMap<String, i128> = self
+            .constants
+            .iter()
+            .map(|(name, &(value, _))| (name.clone(), value))
+            .collect();
+        let mut output = vec![];
+        for (index, section) in sections.iter().enumerate() {
+            match section.script.assemble(&scripts, &constants, index) {
+                Ok(bytes) => output.push(Bytes::from(bytes)),
+                Err(errors) => {
+                    for e in errors {
+                        self.encode_error(&e);
+                    }
+                }
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(output)
+        } else {
+            self.errors.sort_by_key(|&(line, ref e)| (line, e.column));
+            Err(self.errors.into_iter().map(|(_, e)| e).collect())
+        }
+    }
+
+    fn encode_error(&mut self, e: &Located) {
+        let expected = match e.error {
+            EncodeError::ImmediateOutOfRange { bits, .. } => {
+                format!("immediate that fits in {} bits", bits)
+            }
+            EncodeError::UndefinedLabel { .. } => "defined label".to_string(),
+            EncodeError::DuplicateLabel { ref name, line } => {
+                let found = format!("`{}`, first defined on {}", name, self.place(line, e.line));
+                let column = self.column(e.line, e.at);
+                return self.error(e.line, column, "unique label", found);
+            }
+            EncodeError::UndefinedScript { .. } => "defined script name".to_string(),
+            EncodeError::UndefinedConstant { .. } => "defined constant".to_string(),
+            EncodeError::CallOutOfRange { .. } => {
+                "script at most 256 sections after the caller".to_string()
+            }
+            EncodeError::InvalidRegister { .. } => "register".to_string(),
+            ref other => other.to_string(),
+        };
+        self.expected(e.line, e.at, &expected);
+    }
+}
+
+// What the parser wanted where it failed with `code`
+fn describe(code: u32) -> String {
+    let expected = match code {
+        ERR_REGISTER_INDEX => return format!("register index below {}", REGSIZE),
+        ERR_REGISTER_WIDTH => "register width .32, .64 or .128",
+        ERR_MNEMONIC => "mnemonic",
+        ERR_REGISTER => "register",
+        ERR_IMMEDIATE => "immediate",
+        ERR_BYTE => "byte",
+        ERR_TARGET => "jump target or label",
+        ERR_CALL => "script name or index",
+        ERR_END_OF_LINE => "end of line",
+        ERR_DIRECTIVE => "directive .script, .equ or .include",
+        ERR_SCRIPT_NAME => "script name",
+        ERR_CONSTANT_NAME => "constant name",
+        ERR_CONSTANT_VALUE => "constant value",
+        ERR_FILE_NAME => "quoted file name",
+        _ => "statement",
+    };
+    expected.to_string()
+}
+
+// The next word of `rest` before any comment, quoted, for the "found" half of a diagnostic
+fn found(rest: &str) -> String {
+    let code = rest.split(&[';', '#'][..]).next().unwrap_or("");
+    match code.split_whitespace().next() {
+        Some(word) => format!("`{}`", word),
+        None => "end of line".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruction::Opcode;
+    use vm::VM;
+
+    #[test]
+    fn test_assemble_program() {
+        let source = "\
+            ld r0.64 0x10\n\
+            \n\
+            loop:\n\
+            \tsubi r0.64 1\n\
+            \tcmpi r0.64 0\n\
+            \tjgt loop\n\
+            \tcal lib\n\
+            \thlt\n\
+            .script lib\n\
+            \tpsh r0.64\n\
+            \tret\n";
+        let scripts = assemble("loop.gasm", source).unwrap();
+        assert_eq!(scripts.len(), 2);
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(vm.run(1000).is_ok());
+        assert_eq!(vm.heap.to_vec(), vec![0; 8]);
+    }
+
+    fn run(source: &str) -> Vec<u8> {
+        let scripts = assemble("test.gasm", source).unwrap();
+        let mut vm = VM::new(&scripts).unwrap();
+        vm.run(1000).unwrap();
+        vm.heap.to_vec()
+    }
+
+    #[test]
+    fn test_program_sections() {
+        let main = ".script main\ncal one\ncal two\nhlt\n";
+        let one = ".script one\nld r0 1\npsh r0\nret\n";
+        let two = ".script two\nld r1 2\npsh r1\nret\n";
+
+        let expected = vec![1, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(run(&format!("{}{}{}", main, one, two)), expected);
+        // Reordering the callees recomputes every CAL index
+        assert_eq!(run(&format!("{}{}{}", main, two, one)), expected);
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let errors = assemble("test.gasm", ".script a\nhlt\n.script a\nret\n").unwrap_err();
+        assert_eq!(errors[0].found, "`a`, first defined on line 1");
+
+        // CAL only reaches scripts after the caller
+        let errors = assemble("test.gasm", ".script a\nhlt\n.script b\ncal a\nret\n").unwrap_err();
+        assert_eq!(
+            (
+                errors[0].line,
+                errors[0].column,
+                errors[0].expected.as_str()
+            ),
+            (4, 5, "script at most 256 sections after the caller")
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let source = "ld r0 5\nadd r0 x5\nfoo r1\nld r1 0x100000000\njmp nowhere;far\nhlt extra\nr: nop\nr: hlt\n";
+        let errors = assemble("bad.gasm", source).unwrap_err();
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|e| (e.line, e.column, e.expected.as_str(), e.found.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, 8, "register", "`x5`"),
+                (3, 1, "mnemonic", "`foo`"),
+                (4, 7, "immediate that fits in 32 bits", "`0x100000000`"),
+                (5, 5, "defined label", "`nowhere`"),
+                (6, 5, "end of line", "`extra`"),
+                (8, 1, "unique label", "`r`, first defined on line 7"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_register_errors() {
+        let errors = assemble("regs.gasm", "inc r63\ninc r1.16\n").unwrap_err();
+        assert_eq!(errors[0].expected, "register index below 63");
+        assert_eq!((errors[1].column, errors[1].found.as_str()), (5, "`r1.16`"));
+    }
+
+    #[test]
+    fn test_assemble_script_errors() {
+        let source = ".script main\ncal lib\nhlt\n.script\n.script main\nret\n";
+        let errors = assemble("calls.gasm", source).unwrap_err();
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|e| (e.line, e.column, e.expected.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, 5, "defined script name"),
+                (4, 8, "script name"),
+                (5, 9, "unique script name")
+            ]
+        );
+        assert!(assemble("empty.gasm", "\n\n").is_err());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = assemble("bad.gasm", "nop\n\tadd r0 x5\n").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "bad.gasm:2:9: expected register, found `x5`\n  |\n2 | \tadd r0 x5\n  | \t       ^"
+        );
+    }
+
+    #[test]
+    fn test_comments_and_case() {
+        let source = "; counts down\n\n  LD R0 3   # start\nLoop: SUBI r0 1 ; step\nCmpI r0.32 0\nJGT Loop\nPSH D1\nHlt\n";
+        let scripts = assemble("case.gasm", source).unwrap();
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(vm.run(1000).is_ok());
+        assert_eq!(scripts[0][6], Opcode::SUBI as u8);
+        assert_eq!(vm.heap.len(), 8);
+    }
+
+    #[test]
+    fn test_equ() {
+        let source = ".equ SHIFT 4\n.equ MASK 0xF0 ; high nibble\nld r0 0xFF\nandi r0 MASK\nshru r0 SHIFT\npsh r0\nhlt\n";
+        let scripts = assemble("equ.gasm", source).unwrap();
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(vm.run(1000).is_ok());
+        assert_eq!(vm.heap.to_vec(), vec![0xF, 0, 0, 0]);
+
+        let source = ".equ A 1\n.equ A 2\n.equ B\n.equ C x\n.foo\nshl r0 D\nhlt\n";
+        let errors = assemble("equ.gasm", source).unwrap_err();
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|e| (e.line, e.column, e.expected.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, 6, "unique constant"),
+                (3, 7, "constant value"),
+                (4, 8, "constant value"),
+                (5, 1, "directive .script, .equ or .include"),
+                (6, 8, "defined constant"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let mut files = HashMap::new();
+        files.insert("lib/consts.gasm", ".equ TEN 10\n");
+        files.insert(
+            "lib/util.gasm",
+            ".include \"consts.gasm\"\n.script util\nld r1 TEN\nret\n",
+        );
+        files.insert("lib/bad.gasm", "nop\nadd r0 x5\n");
+        files.insert("lib/loop.gasm", ".include \"loop.gasm\"\n");
+        let mut load = |path: &str| {
+            files
+                .get(path)
+                .map(|s| s.to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        };
+
+        let source =
+            "ld r0 TEN ; defined in an include\ncal util\nhlt\n.include \"lib/util.gasm\"\n";
+        let scripts = assemble_with("main.gasm", source, &mut load).unwrap();
+        assert_eq!(scripts.len(), 2);
+
+        let source =
+            "nop\n.include \"lib/bad.gasm\"\n.include \"lib/missing.gasm\"\n.include lib\n";
+        let errors = assemble_with("main.gasm", source, &mut load).unwrap_err();
+        let summary: Vec<_> = errors
+            .iter()
+            .map(|e| (e.file.as_str(), e.line, e.column, e.expected.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("lib/bad.gasm", 2, 8, "register"),
+                ("main.gasm", 3, 10, "readable file"),
+                ("main.gasm", 4, 10, "quoted file name"),
+            ]
+        );
+
+        let errors =
+            assemble_with("main.gasm", ".include \"lib/loop.gasm\"\n", &mut load).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file, "lib/loop.gasm");
+        assert_eq!(errors[0].expected, "includes nested at most 16 deep");
+    }
+}
@@ -1,8 +1,12 @@
+use nom::{Context, Err, IResult};
 use nom::types::CompleteStr;
-use asm::Token;
-use asm::opcode_parser::opcode_load;
-use asm::arg_parser::i32_arg;
+use asm::{fail, offset, EncodeError, Symbols, Token};
+use asm::{ERR_BYTE, ERR_CALL, ERR_IMMEDIATE, ERR_MNEMONIC, ERR_REGISTER, ERR_TARGET};
+use asm::opcode_parser::opcode;
+use asm::arg_parser::{byte_arg, imm_arg, target_arg};
+use asm::label_parser::label_usage;
 use asm::reg_parser::register;
+use instruction::{JumpMode, Opcode, Operand, RegLocal};
 
 #[derive(Debug, PartialEq)]
 pub struct AsmInstruction {
@@ -13,90 +17,251 @@ pub struct AsmInstruction {
 }
 
 impl AsmInstruction {
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn new(code: Opcode, operands: Vec<Token>) -> AsmInstruction {
+        let mut operands = operands.into_iter();
+        AsmInstruction {
+            opcode: Token::Op { code },
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+        }
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>, EncodeError> {
+        self.encode(&Symbols::default())
+    }
+
+    /// Encodes the instruction, resolving label operands against `symbols`
+    pub fn encode(&self, symbols: &Symbols) -> Result<Vec<u8>, EncodeError> {
         let mut results = vec![];
-        match self.opcode {
-            Token::Op { code } => match code {
-                _ => {
-                    results.push(code as u8);
-                }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
-            }
-        };
+        let code = self.code()?;
+        results.push(code as u8);
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(t) => AsmInstruction::extract_operand(t, &mut results),
-                None => {}
-            }
+        // Immediates are encoded at the width of the register they are combined with
+        let mut width = None;
+        for (kind, t) in code.operands().iter().zip(self.operands()) {
+            AsmInstruction::extract_operand(code, *kind, t, symbols, &mut width, &mut results)?;
+        }
+
+        Ok(results)
+    }
+
+    /// Encoded length in bytes, known before any label is resolved
+    pub fn size(&self) -> Result<usize, EncodeError> {
+        let code = self.code()?;
+        let width = self.operands().find_map(|t| match *t {
+            Token::Reg { reg_num } => RegLocal::decode(reg_num),
+            _ => None,
+        });
+        Ok(1 + code.operands().iter().map(|kind| match kind {
+            Operand::Reg | Operand::Byte => 1,
+            Operand::Imm => width.unwrap_or(RegLocal::REG32).size(),
+            Operand::Target => 5,
+        }).sum::<usize>())
+    }
+
+    fn code(&self) -> Result<Opcode, EncodeError> {
+        match self.opcode {
+            Token::Op { code } => Ok(code),
+            ref t => Err(EncodeError::UnexpectedToken { found: format!("{:?}", t) }),
         }
+    }
 
-        results
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+        self.operand1.iter().chain(self.operand2.iter()).chain(self.operand3.iter())
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
+    fn extract_operand(code: Opcode, kind: Operand, t: &Token, symbols: &Symbols, width: &mut Option<RegLocal>, results: &mut Vec<u8>) -> Result<(), EncodeError> {
     match t {
         Token::Reg { reg_num } => {
+            let bank = RegLocal::decode(*reg_num).ok_or(EncodeError::InvalidRegister { reg: *reg_num })?;
+            width.get_or_insert(bank);
             results.push(*reg_num);
         }
         Token::Number { value } => {
-            let converted = *value as u16;
-            let byte1 = converted;
-            let byte2 = converted >> 8;
-            results.push(byte2 as u8);
-            results.push(byte1 as u8);
+            let size = width.unwrap_or(RegLocal::REG32).size();
+            let bits = size * 8;
+            let fits = bits == 128 || (*value >= -(1i128 << (bits - 1)) && *value < (1i128 << bits));
+            if !fits {
+                return Err(EncodeError::ImmediateOutOfRange { value: *value, bits });
+            }
+            results.extend_from_slice(&value.to_be_bytes()[16 - size..]);
+        }
+        Token::Byte { value } => {
+            results.push(*value);
+        }
+        Token::Target { relative, offset } => {
+            let mode = if *relative { JumpMode::Relative } else { JumpMode::Absolute };
+            results.push(mode as u8);
+            results.extend_from_slice(&(*offset as u32).to_be_bytes());
         }
-        _ => {
-            println!("Opcode found in operand field");
-            std::process::exit(1);
+        // Jumps to a label use its absolute offset
+        Token::LabelUsage { name } if kind == Operand::Target => {
+            let offset = symbols.labels.get(name).ok_or_else(|| EncodeError::UndefinedLabel { name: name.clone() })?;
+            results.push(JumpMode::Absolute as u8);
+            results.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+        // CAL takes the callee's index relative to the script after the caller
+        Token::LabelUsage { name } if code == Opcode::CAL && (symbols.scripts.contains_key(name) || !symbols.constants.contains_key(name)) => {
+            let index = symbols.scripts.get(name).ok_or_else(|| EncodeError::UndefinedScript { name: name.clone() })?;
+            let relative = index.checked_sub(symbols.script + 1).filter(|i| *i <= u8::MAX as usize);
+            results.push(relative.ok_or_else(|| EncodeError::CallOutOfRange { name: name.clone() })? as u8);
+        }
+        // Any other name is a `.equ` constant
+        Token::LabelUsage { name } => {
+            let value = *symbols.constants.get(name).ok_or_else(|| EncodeError::UndefinedConstant { name: name.clone() })?;
+            if kind != Operand::Byte {
+                return AsmInstruction::extract_operand(code, kind, &Token::Number { value }, symbols, width, results);
+            }
+            if value < 0 || value > u8::MAX as i128 {
+                return Err(EncodeError::ImmediateOutOfRange { value, bits: 8 });
+            }
+            results.push(value as u8);
         }
+        t => return Err(EncodeError::UnexpectedToken { found: format!("{:?}", t) }),
     };
+    Ok(())
 }
 
 }
 
-/// Handles instructions of the following form:
-/// LOAD $0 #100
-named!(pub instruction_one<CompleteStr, AsmInstruction>,
-    do_parse!(
-        o: opcode_load >>
-        r: register >>
-        i: i32_arg >>
-        (
-            AsmInstruction{
-                opcode: o,
-                operand1: Some(r),
-                operand2: Some(i),
-                operand3: None
-            }
-        )
-    )
-);
+// Parses one operand of kind `kind`
+pub fn operand(input: CompleteStr, kind: Operand) -> IResult<CompleteStr, Token> {
+    match kind {
+        Operand::Reg => register(input),
+        Operand::Imm => alt!(input, imm_arg | label_usage),
+        Operand::Byte => alt!(input, byte_arg | label_usage),
+        Operand::Target => alt!(input, target_arg | label_usage),
+    }
+}
 
+/// Handles any instruction, e.g.:
+/// hlt
+/// ld r0 100
+/// add r0 r1
+/// shl r0 4
+/// jeq 12
+/// jne loop
+/// cal lib
+/// Also returns the offset from the start of `input` of the mnemonic and of each operand.
+/// Once the mnemonic is matched, a missing or malformed operand is a hard failure whose
+/// `ErrorKind::Custom` code says what was expected.
+pub fn instruction(input: CompleteStr) -> IResult<CompleteStr, (AsmInstruction, Vec<usize>)> {
+    let mut at = vec![offset(input, input)];
+    let (mut rest, code) = match opcode(input) {
+        Ok((r, Token::Op { code })) => (r, code),
+        _ => return fail(input, ERR_MNEMONIC),
+    };
+    let mut tokens = vec![];
+    for kind in code.operands() {
+        at.push(offset(input, rest));
+        let (r, t) = match operand(rest, *kind) {
+            Ok(ok) => ok,
+            // The register parser knows better what is wrong, but the whole operand is reported
+            Err(Err::Failure(Context::Code(_, e))) => return Err(Err::Failure(Context::Code(rest, e))),
+            Err(_) => {
+                let err = match *kind {
+                    Operand::Reg => ERR_REGISTER,
+                    Operand::Imm => ERR_IMMEDIATE,
+                    Operand::Byte if code == Opcode::CAL => ERR_CALL,
+                    Operand::Byte => ERR_BYTE,
+                    Operand::Target => ERR_TARGET,
+                };
+                return fail(rest, err);
+            }
+        };
+        rest = r;
+        tokens.push(t);
+    }
+    Ok((rest, (AsmInstruction::new(code, tokens), at)))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use asm::Opcode;
+    use nom::ErrorKind;
+    use asm::ERR_REGISTER_INDEX;
 
     #[test]
     fn test_parse_instruction_form_one() {
-        let result = instruction_one(CompleteStr("ld r0 i32100\n"));
+        let result = instruction(CompleteStr("ld r0 i32100\n"));
         assert_eq!(
             result,
             Ok((
                 CompleteStr(""),
-                AsmInstruction {
-                    //label: None,
+                (AsmInstruction {
                     opcode: Token::Op { code: Opcode::LOD },
                     operand1: Some(Token::Reg { reg_num: 0 }),
                     operand2: Some(Token::Number { value: 100 }),
                     operand3: None
-                }
-            ))
+                },
+                vec![0, 3, 6]
+            )))
         );
+
+        let result = instruction(CompleteStr("ld r0 100\n"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                (AsmInstruction {
+                    opcode: Token::Op { code: Opcode::LOD },
+                    operand1: Some(Token::Reg { reg_num: 0 }),
+                    operand2: Some(Token::Number { value: 100 }),
+                    operand3: None
+                },
+                vec![0, 3, 6]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_imm() {
+        let result = instruction(CompleteStr("cmpi r1 5\n"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                (AsmInstruction {
+                    opcode: Token::Op { code: Opcode::CMPI },
+                    operand1: Some(Token::Reg { reg_num: 1 }),
+                    operand2: Some(Token::Number { value: 5 }),
+                    operand3: None
+                },
+                vec![0, 5, 8]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction() {
+        let result = instruction(CompleteStr("jne -6\n"));
+        let (rest, (inst, _)) = result.unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(
+            inst.to_bytes().unwrap(),
+            vec![Opcode::JNE as u8, JumpMode::Relative as u8, 0xFF, 0xFF, 0xFF, 0xFA]
+        );
+
+        let (_, (inst, _)) = instruction(CompleteStr("jmp 12")).unwrap();
+        assert_eq!(
+            inst.to_bytes().unwrap(),
+            vec![Opcode::JMP as u8, JumpMode::Absolute as u8, 0, 0, 0, 12]
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_errors() {
+        let failure = |input| match instruction(CompleteStr(input)) {
+            Err(Err::Failure(Context::Code(rest, ErrorKind::Custom(code)))) => Some((rest.0, code)),
+            _ => None,
+        };
+        assert_eq!(failure("foo r1"), Some(("foo r1", ERR_MNEMONIC)));
+        assert_eq!(failure("add r0 x5"), Some(("x5", ERR_REGISTER)));
+        assert_eq!(failure("ld r0"), Some(("", ERR_IMMEDIATE)));
+        assert_eq!(failure("cal -1"), Some(("-1", ERR_CALL)));
+        assert_eq!(failure("jmp +x"), Some(("+x", ERR_TARGET)));
+        // Register errors keep their own code but point at the start of the operand
+        assert_eq!(failure("inc  r63"), Some(("r63", ERR_REGISTER_INDEX)));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,63 @@
+use nom::types::CompleteStr;
+use asm::Token;
+
+/// Label and script names: a letter or `_`, then letters, digits or `_`
+named!(pub identifier<CompleteStr, String>,
+    map!(
+        verify!(
+            take_while1!(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            |s: CompleteStr| !s.starts_with(|c: char| c.is_ascii_digit())
+        ),
+        |s: CompleteStr| s.to_string()
+    )
+);
+
+/// Declares a label at the offset of the instruction that follows it:
+/// loop: add r0 r1
+named!(pub label_declaration<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            name: identifier >>
+            tag!(":") >>
+            (
+                Token::LabelDeclaration{name}
+            )
+        )
+    )
+);
+
+/// A symbolic operand, resolved once the layout of every label is known:
+/// jne loop
+/// cal lib
+named!(pub label_usage<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            name: identifier >>
+            (
+                Token::LabelUsage{name}
+            )
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration(CompleteStr("loop_1: "));
+        assert_eq!(result, Ok((CompleteStr(""), Token::LabelDeclaration { name: "loop_1".to_string() })));
+
+        assert!(label_declaration(CompleteStr("loop")).is_err());
+        assert!(label_declaration(CompleteStr("1loop:")).is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage(CompleteStr("_start\n"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::LabelUsage { name: "_start".to_string() })));
+
+        assert!(label_usage(CompleteStr("-6")).is_err());
+    }
+}
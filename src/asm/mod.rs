@@ -1,13 +1,112 @@
+// The parsers are `named!` invocations, which rustdoc skips, but their doc comments still
+// describe the syntax they accept
+#![allow(unused_doc_comments)]
+#![allow(clippy::bool_assert_comparison, clippy::redundant_field_names)]
+
 use instruction::Opcode;
 pub mod arg_parser;
+pub mod assembler;
+pub mod label_parser;
 pub mod opcode_parser;
 pub mod reg_parser;
 pub mod inst_parser;
 pub mod script_parser;
 
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use nom::types::CompleteStr;
+use nom::{Context, Err, ErrorKind, IResult};
+
+// Codes carried by `nom::ErrorKind::Custom` when a parser fails past the point of backtracking
+pub const ERR_REGISTER_INDEX: u32 = 1; // register index not below `REGSIZE`
+pub const ERR_REGISTER_WIDTH: u32 = 2; // register suffix other than .32, .64 or .128
+pub const ERR_MNEMONIC: u32 = 3; // no known mnemonic where an instruction starts
+pub const ERR_REGISTER: u32 = 4; // the operand kinds of `Opcode::operands`
+pub const ERR_IMMEDIATE: u32 = 5;
+pub const ERR_BYTE: u32 = 6;
+pub const ERR_TARGET: u32 = 7;
+pub const ERR_CALL: u32 = 8; // CAL operand that is neither a script name nor an index
+pub const ERR_END_OF_LINE: u32 = 9; // anything but a comment after a complete statement
+pub const ERR_DIRECTIVE: u32 = 10; // a `.` not followed by .script, .equ or .include
+pub const ERR_SCRIPT_NAME: u32 = 11;
+pub const ERR_CONSTANT_NAME: u32 = 12;
+pub const ERR_CONSTANT_VALUE: u32 = 13;
+pub const ERR_FILE_NAME: u32 = 14; // `.include` argument that is not a quoted path
+
+/// A hard failure at `rest`, carrying one of the codes above
+pub fn fail<T>(rest: CompleteStr, code: u32) -> IResult<CompleteStr, T> {
+    Err(Err::Failure(Context::Code(rest, ErrorKind::Custom(code))))
+}
+
+/// Byte offset from the start of `input` of the first non-blank character of `rest`,
+/// a suffix of it
+pub fn offset(input: CompleteStr, rest: CompleteStr) -> usize {
+    input.len() - rest.trim_start().len()
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
     Reg { reg_num: u8 },
-    Number { value: i32 },
+    Number { value: i128 },
+    Byte { value: u8 },
+    Target { relative: bool, offset: i32 },
+    LabelDeclaration { name: String },
+    LabelUsage { name: String },
 }
+
+/// Names visible while encoding one script
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Symbols {
+    /// Label name to its byte offset in the script being encoded
+    pub labels: HashMap<String, usize>,
+    /// Script name to its index in the library slice given to `VM::new`
+    pub scripts: HashMap<String, usize>,
+    /// Index of the script being encoded
+    pub script: usize,
+    /// `.equ` constant name to its value
+    pub constants: HashMap<String, i128>,
+}
+
+/// Errors raised while encoding parsed instructions into bytecode
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodeError {
+    // A token of the wrong kind in an opcode or operand slot
+    UnexpectedToken { found: String },
+    // Register byte whose top bits select no bank
+    InvalidRegister { reg: u8 },
+    // An immediate that does not fit the width of its destination register
+    ImmediateOutOfRange { value: i128, bits: usize },
+    UndefinedLabel { name: String },
+    // `line` is where the label was first defined
+    DuplicateLabel { name: String, line: usize },
+    UndefinedScript { name: String },
+    UndefinedConstant { name: String },
+    // CAL can only reach up to 256 scripts after the caller
+    CallOutOfRange { name: String },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::UnexpectedToken { ref found } => write!(f, "unexpected {}", found),
+            EncodeError::InvalidRegister { reg } => write!(f, "invalid register {:#x}", reg),
+            EncodeError::ImmediateOutOfRange { value, bits } => {
+                write!(f, "immediate {} does not fit in {} bits", value, bits)
+            }
+            EncodeError::UndefinedLabel { ref name } => write!(f, "undefined label '{}'", name),
+            EncodeError::DuplicateLabel { ref name, line } => {
+                write!(f, "label '{}' is already defined on line {}", name, line)
+            }
+            EncodeError::UndefinedScript { ref name } => write!(f, "undefined script '{}'", name),
+            EncodeError::UndefinedConstant { ref name } => write!(f, "undefined constant '{}'", name),
+            EncodeError::CallOutOfRange { ref name } => {
+                write!(f, "script '{}' is not within reach of CAL from this script", name)
+            }
+        }
+    }
+}
+
+impl Error for EncodeError {}
@@ -1,10 +1,15 @@
+use nom::alpha;
 use nom::types::CompleteStr;
 use asm::Token;
 use instruction::Opcode;
 
-named!(pub opcode_load<CompleteStr, Token>,
-  do_parse!(
-      tag!("ld") >> (Token::Op{code: Opcode::LOD})
+/// Any mnemonic known to `Opcode::from_mnemonic`, in any case:
+/// ld, addi, JNE, Hlt...
+named!(pub opcode<CompleteStr, Token>,
+  ws!(
+    do_parse!(
+        code: map_opt!(alpha, |s: CompleteStr| Opcode::from_mnemonic(&s.to_lowercase())) >> (Token::Op{code})
+    )
   )
 );
 
@@ -15,7 +20,7 @@ mod tests {
     #[test]
     fn test_opcode_load() {
         // First tests that the opcode is detected and parsed correctly
-        let result = opcode_load(CompleteStr("ld"));
+        let result = opcode(CompleteStr("ld"));
         assert_eq!(result.is_ok(), true);
 
         let (rest, token) = result.unwrap();
@@ -23,7 +28,37 @@ mod tests {
         assert_eq!(rest, CompleteStr(""));
 
         // Tests that an invalid opcode isn't recognized
-        let result = opcode_load(CompleteStr("aold"));
+        let result = opcode(CompleteStr("aold"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_opcode_imm() {
+        let result = opcode(CompleteStr("xori"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Op{code: Opcode::XORI})));
+
+        let result = opcode(CompleteStr("add"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Op{code: Opcode::ADD})));
+    }
+
+    #[test]
+    fn test_opcode_jump() {
+        let result = opcode(CompleteStr("jle"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Op{code: Opcode::JLE})));
+
+        let result = opcode(CompleteStr("jxx"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_opcode_every_mnemonic() {
+        for b in 0..Opcode::ERR as u8 {
+            let code = Opcode::from(b);
+            let result = opcode(CompleteStr(code.mnemonic()));
+            assert_eq!(result, Ok((CompleteStr(""), Token::Op{code})));
+        }
+        assert!(opcode(CompleteStr("err")).is_err());
+        assert_eq!(opcode(CompleteStr("ADDI")), Ok((CompleteStr(""), Token::Op{code: Opcode::ADDI})));
+        assert_eq!(opcode(CompleteStr("Jge")), Ok((CompleteStr(""), Token::Op{code: Opcode::JGE})));
+    }
 }
\ No newline at end of file
@@ -1,24 +1,61 @@
 use nom::types::CompleteStr;
-use nom::digit;
-use asm::Token;
+use nom::{digit, ErrorKind};
+use asm::{Token, ERR_REGISTER_INDEX, ERR_REGISTER_WIDTH};
+use vm_script::REGSIZE;
 
-named!(pub register<CompleteStr, Token>,
-    ws!( 
-        do_parse!(
-            tag!("r") >>
-            reg_num: digit >>
-            ( 
-                Token::Reg{ 
-                  reg_num: reg_num.parse::<u8>().unwrap() 
-                } 
-            ) 
+/// Bank suffix, mapped to the top two bits of the register byte:
+/// r5.32, r5.64, r5.128
+named!(bank<CompleteStr, u8>,
+    alt!(
+        value!(2 << 6, tag!("128")) |
+        value!(1 << 6, tag!("64")) |
+        value!(0, tag!("32"))
+    )
+);
+
+/// Register prefix. `r` defaults to the 32-bit bank and takes an optional suffix,
+/// the short forms name their bank directly: w5 (32-bit), d5 (64-bit), q5 (128-bit).
+/// Like mnemonics, prefixes are case-insensitive: R5, D5
+named!(prefix<CompleteStr, Option<u8>>,
+    alt!(
+        value!(None, tag_no_case!("r")) |
+        value!(Some(0), tag_no_case!("w")) |
+        value!(Some(1 << 6), tag_no_case!("d")) |
+        value!(Some(2 << 6), tag_no_case!("q"))
+    )
+);
+
+fn index(digits: CompleteStr) -> Option<u8> {
+    digits.parse::<usize>().ok().filter(|i| *i < REGSIZE).map(|i| i as u8)
+}
+
+/// Once a prefix and digits are matched, a bad index or suffix is a hard failure
+/// rather than a reason to try another operand shape
+named!(reg_token<CompleteStr, Token>,
+    do_parse!(
+        fixed: prefix >>
+        digits: digit >>
+        idx: return_error!(ErrorKind::Custom(ERR_REGISTER_INDEX), expr_opt!(index(digits))) >>
+        suffix: cond!(fixed.is_none(), opt!(preceded!(
+            tag!("."),
+            return_error!(ErrorKind::Custom(ERR_REGISTER_WIDTH), bank)
+        ))) >>
+        (
+            Token::Reg{
+              reg_num: idx | fixed.or_else(|| suffix.and_then(|b| b)).unwrap_or(0)
+            }
         )
     )
 );
 
+named!(pub register<CompleteStr, Token>,
+    ws!(reg_token)
+);
+
 #[cfg(test)]
 mod tests {
 use super::*;
+use nom::{Context, Err};
 
 
 #[test]
@@ -30,4 +67,32 @@ use super::*;
       let result = register(CompleteStr("ra"));
       assert_eq!(result.is_ok(), false);
   }
+
+#[test]
+  fn test_parse_register_bank() {
+      assert_eq!(register(CompleteStr("r5.32")), Ok((CompleteStr(""), Token::Reg { reg_num: 5 })));
+      assert_eq!(register(CompleteStr("r5.64")), Ok((CompleteStr(""), Token::Reg { reg_num: 0x45 })));
+      assert_eq!(register(CompleteStr("r5.128")), Ok((CompleteStr(""), Token::Reg { reg_num: 0x85 })));
+      assert_eq!(register(CompleteStr("w5")), Ok((CompleteStr(""), Token::Reg { reg_num: 5 })));
+      assert_eq!(register(CompleteStr("d5")), Ok((CompleteStr(""), Token::Reg { reg_num: 0x45 })));
+      assert_eq!(register(CompleteStr("q62")), Ok((CompleteStr(""), Token::Reg { reg_num: 0xBE })));
+      assert_eq!(register(CompleteStr("R5.64")), Ok((CompleteStr(""), Token::Reg { reg_num: 0x45 })));
+      assert_eq!(register(CompleteStr("D5")), Ok((CompleteStr(""), Token::Reg { reg_num: 0x45 })));
+      assert_eq!(register(CompleteStr("Q62")), Ok((CompleteStr(""), Token::Reg { reg_num: 0xBE })));
+  }
+
+#[test]
+  fn test_parse_register_errors() {
+      let failure = |input, code| match register(CompleteStr(input)) {
+          Err(Err::Failure(Context::Code(_, ErrorKind::Custom(c)))) => c == code,
+          _ => false,
+      };
+      assert!(failure("r63", ERR_REGISTER_INDEX));
+      assert!(failure("r200", ERR_REGISTER_INDEX));
+      assert!(failure("q99999999999999999999999", ERR_REGISTER_INDEX));
+      assert!(failure("r5.16", ERR_REGISTER_WIDTH));
+      // Not a register at all, so other operand parsers may still match
+      assert!(matches!(register(CompleteStr("x5")), Err(Err::Error(_))));
+      assert!(matches!(register(CompleteStr("d")), Err(Err::Error(_))));
+  }
 }
\ No newline at end of file
@@ -1,34 +1,299 @@
+use nom::IResult;
 use nom::types::CompleteStr;
 
-use asm::inst_parser::{instruction_one, AsmInstruction};
+use std::collections::HashMap;
 
+use asm::{fail, offset, EncodeError, Symbols, Token};
+use asm::{ERR_CONSTANT_NAME, ERR_CONSTANT_VALUE, ERR_DIRECTIVE, ERR_END_OF_LINE, ERR_FILE_NAME, ERR_SCRIPT_NAME};
+use asm::arg_parser::imm_arg;
+use asm::inst_parser::{instruction, AsmInstruction};
+use asm::label_parser::{identifier, label_declaration};
+
+/// One statement of a source line. `at` is the byte offset within the line of the token
+/// the statement is reported at: the label, the directive's argument, or the mnemonic
+/// followed by each operand.
 #[derive(Debug, PartialEq)]
+pub enum Statement {
+    Label { name: String, at: usize },
+    Instruction { inst: AsmInstruction, at: Vec<usize> },
+    // `.script name` starts the next script of the library
+    Script { name: String, at: usize },
+    // `.equ NAME value` defines a constant for immediate and byte operands
+    Equ { name: String, value: i128, at: usize },
+    // `.include "file"` splices another file in after this line
+    Include { path: String, at: usize },
+}
+
+/// An `EncodeError` with the line of the statement that raised it and the offset within
+/// that line of the token it is about
+#[derive(Debug, PartialEq, Clone)]
+pub struct Located {
+    pub line: usize,
+    pub at: usize,
+    pub error: EncodeError,
+}
+
+/// The labels and instructions of one script, each with the line it was parsed from.
+/// A library's scripts are its `.script` sections, plus any instructions before the first.
+#[derive(Debug, Default, PartialEq)]
 pub struct Script {
-    instructions: Vec<AsmInstruction>,
+    statements: Vec<(usize, Statement)>,
 }
 
-named!(pub script<CompleteStr, Script>,
-    do_parse!(
-        instructions: many1!(instruction_one) >>
-        (
-            Script {
-                instructions: instructions
+impl Script {
+    /// Appends a statement parsed from `line`. Only labels and instructions belong to the
+    /// script; directives are for the caller, which knows about files and other scripts.
+    pub fn push(&mut self, line: usize, statement: Statement) {
+        self.statements.push((line, statement));
+    }
+
+    /// Assembles the script as entry `index` of a library whose script names map to `scripts`.
+    /// The first pass lays out every instruction to find the label offsets, the second encodes.
+    /// Every error is returned, not just the first.
+    pub fn assemble(&self, scripts: &HashMap<String, usize>, constants: &HashMap<String, i128>, index: usize) -> Result<Vec<u8>, Vec<Located>> {
+        let (labels, mut errors) = self.layout();
+        let symbols = Symbols {
+            labels,
+            scripts: scripts.clone(),
+            script: index,
+            constants: constants.clone(),
+        };
+        let mut results = vec![];
+        for &(line, ref statement) in &self.statements {
+            if let Statement::Instruction { ref inst, ref at } = *statement {
+                match inst.encode(&symbols) {
+                    Ok(bytes) => results.extend(bytes),
+                    Err(error) => errors.push(Located { line, at: locate(inst, at, &error), error }),
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(results)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Byte offset of every label, as the VM's pc will see it. A label defined twice keeps
+    /// its first offset, and each redefinition is returned as an error.
+    pub fn layout(&self) -> (HashMap<String, usize>, Vec<Located>) {
+        let mut labels = HashMap::new();
+        let mut lines = HashMap::new();
+        let mut errors = vec![];
+        let mut offset = 0;
+        for &(line, ref statement) in &self.statements {
+            match *statement {
+                Statement::Label { ref name, at } => {
+                    if let Some(&first) = lines.get(name) {
+                        let error = EncodeError::DuplicateLabel { name: name.clone(), line: first };
+                        errors.push(Located { line, at, error });
+                        continue;
+                    }
+                    lines.insert(name.clone(), line);
+                    labels.insert(name.clone(), offset);
+                }
+                Statement::Instruction { ref inst, .. } => offset += inst.size().unwrap_or(0),
+                _ => {}
             }
-        )
+        }
+        (labels, errors)
+    }
+}
+
+// Offset of the operand `error` is about, or of the mnemonic
+fn locate(inst: &AsmInstruction, at: &[usize], error: &EncodeError) -> usize {
+    let slot = inst.operands().position(|t| match (error, t) {
+        (&EncodeError::InvalidRegister { reg }, &Token::Reg { reg_num }) => reg == reg_num,
+        (&EncodeError::ImmediateOutOfRange { .. }, &Token::Number { .. })
+        | (&EncodeError::ImmediateOutOfRange { .. }, &Token::LabelUsage { .. }) => true,
+        (&EncodeError::UndefinedLabel { ref name }, &Token::LabelUsage { name: ref n })
+        | (&EncodeError::UndefinedScript { ref name }, &Token::LabelUsage { name: ref n })
+        | (&EncodeError::CallOutOfRange { ref name }, &Token::LabelUsage { name: ref n })
+        | (&EncodeError::UndefinedConstant { ref name }, &Token::LabelUsage { name: ref n }) => name == n,
+        _ => false,
+    });
+    slot.map_or(at[0], |i| at[i + 1])
+}
+
+/// Trailing blanks and an optional `;` or `#` comment, up to and including the line ending
+named!(end_of_line<CompleteStr, ()>,
+    do_parse!(
+        opt!(is_a!(" \t\r")) >>
+        opt!(preceded!(one_of!(";#"), take_till!(|c| c == '\n'))) >>
+        alt!(eof!() | tag!("\n")) >>
+        ()
     )
 );
 
+/// Handles the directives, with offsets from the start of `input`:
+/// .script lib
+/// .equ MASK 0xF0
+/// .include "lib/util.gasm"
+fn directive<'a>(input: CompleteStr<'a>, rest: CompleteStr<'a>) -> IResult<CompleteStr<'a>, Statement> {
+    let (args, name) = take_while1!(CompleteStr(rest.trim_start()), |c: char| c == '.' || c.is_ascii_alphanumeric())?;
+    let arg = CompleteStr(args.trim_start());
+    let at = offset(input, args);
+    match name.0 {
+        ".script" => match identifier(arg) {
+            Ok((r, name)) => Ok((r, Statement::Script { name, at })),
+            Err(_) => fail(args, ERR_SCRIPT_NAME),
+        },
+        ".equ" => {
+            let (r, name) = match identifier(arg) {
+                Ok(ok) => ok,
+                Err(_) => return fail(args, ERR_CONSTANT_NAME),
+            };
+            match imm_arg(r) {
+                Ok((r, Token::Number { value })) => Ok((r, Statement::Equ { name, value, at })),
+                _ => fail(r, ERR_CONSTANT_VALUE),
+            }
+        }
+        ".include" => match arg.0.get(1..).and_then(|a| a.find('"')).filter(|_| arg.starts_with('"')) {
+            Some(end) => {
+                let path = arg.0[1..end + 1].to_string();
+                Ok((CompleteStr(&arg.0[end + 2..]), Statement::Include { path, at }))
+            }
+            None => fail(args, ERR_FILE_NAME),
+        },
+        _ => fail(rest, ERR_DIRECTIVE),
+    }
+}
+
+/// Parses one line of source: an optional `label:`, then an instruction or a directive,
+/// then an optional `;` or `#` comment. Blank and comment-only lines have no statements.
+/// Offsets are from the start of `input`, and a malformed line is a hard failure whose
+/// `ErrorKind::Custom` code says what was expected.
+pub fn line(input: CompleteStr) -> IResult<CompleteStr, Vec<Statement>> {
+    let mut statements = vec![];
+    let mut rest = input;
+    if let Ok((r, Token::LabelDeclaration { name })) = label_declaration(rest) {
+        statements.push(Statement::Label { name, at: offset(input, rest) });
+        rest = r;
+    }
+    if let Ok((r, _)) = end_of_line(rest) {
+        return Ok((r, statements));
+    }
+
+    let (rest, statement) = if rest.trim_start().starts_with('.') {
+        directive(input, rest)?
+    } else {
+        let base = input.len() - rest.len();
+        let (r, (inst, at)) = instruction(rest)?;
+        (r, Statement::Instruction { inst, at: at.iter().map(|a| base + a).collect() })
+    };
+    statements.push(statement);
+    match end_of_line(rest) {
+        Ok((r, _)) => Ok((r, statements)),
+        Err(_) => fail(rest, ERR_END_OF_LINE),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::Bytes;
+    use instruction::{JumpMode, Opcode};
+    use nom::{Context, Err, ErrorKind};
+    use vm::VM;
+    use vm_script::ExitStatus;
+
+    // One script from every line of `source`
+    fn parse(source: &str) -> Script {
+        let mut script = Script::default();
+        for (i, text) in source.lines().enumerate() {
+            for statement in line(CompleteStr(text)).unwrap().1 {
+                script.push(i + 1, statement);
+            }
+        }
+        script
+    }
 
     #[test]
     fn test_parse_program() {
-        let result = script(CompleteStr("ld r0 i32100\n"));
+        let result = line(CompleteStr("ld r0 100\n"));
         assert_eq!(result.is_ok(), true);
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, CompleteStr(""));
-        assert_eq!(1, p.instructions.len());
+        assert_eq!(1, p.len());
         // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
     }
+
+    #[test]
+    fn test_parse_comments_and_directives() {
+        let statements = |text| line(CompleteStr(text)).unwrap().1;
+        assert!(statements("").is_empty());
+        assert!(statements("  ; only a comment").is_empty());
+        assert_eq!(statements("loop: # counts down"), vec![Statement::Label { name: "loop".to_string(), at: 0 }]);
+        match statements("  a: jne a ; back").as_slice() {
+            [Statement::Label { at: 2, .. }, Statement::Instruction { at, .. }] => assert_eq!(at, &vec![5, 9]),
+            s => panic!("unexpected {:?}", s),
+        }
+        assert_eq!(statements(".script lib"), vec![Statement::Script { name: "lib".to_string(), at: 8 }]);
+        assert_eq!(statements(".equ MASK 0xF0 ; high nibble"), vec![Statement::Equ { name: "MASK".to_string(), value: 0xF0, at: 5 }]);
+        assert_eq!(statements(".include \"a;b.gasm\""), vec![Statement::Include { path: "a;b.gasm".to_string(), at: 9 }]);
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        let failure = |input| match line(CompleteStr(input)) {
+            Err(Err::Failure(Context::Code(rest, ErrorKind::Custom(code)))) => Some((offset(CompleteStr(input), rest), code)),
+            _ => None,
+        };
+        assert_eq!(failure("hlt extra"), Some((4, ERR_END_OF_LINE)));
+        assert_eq!(failure(".foo"), Some((0, ERR_DIRECTIVE)));
+        assert_eq!(failure(".script"), Some((7, ERR_SCRIPT_NAME)));
+        assert_eq!(failure(".equ 5"), Some((5, ERR_CONSTANT_NAME)));
+        assert_eq!(failure(".equ A x"), Some((7, ERR_CONSTANT_VALUE)));
+        assert_eq!(failure(".include lib"), Some((9, ERR_FILE_NAME)));
+    }
+
+    #[test]
+    fn test_assembled_script_runs() {
+        let source = "ld r0 -3\nld r1 10\nadd r0 r1\ncmpi r0 7\njne 0\ncal 0\nhlt\n";
+        let main = parse(source).assemble(&HashMap::new(), &HashMap::new(), 0).unwrap();
+        let lib = parse("shl r0 2\nret\n").assemble(&HashMap::new(), &HashMap::new(), 1).unwrap();
+        let scripts = [Bytes::from(main), Bytes::from(lib)];
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(matches!(vm.run(1000), Ok(ExitStatus::Halted { .. })));
+    }
+
+    #[test]
+    fn test_labels() {
+        // Counts r0 down from 3, then calls `lib`
+        let source = "ld r0 3\nloop:\nsubi r0 1\ncmpi r0 0\njgt loop\njmp done\nnop\ndone: cal lib\nhlt\n";
+        let p = parse(source);
+        let (labels, errors) = p.layout();
+        assert_eq!((labels["loop"], labels["done"]), (6, 31));
+        assert!(errors.is_empty());
+
+        let mut names = HashMap::new();
+        names.insert("lib".to_string(), 2);
+        let bytes = p.assemble(&names, &HashMap::new(), 0).unwrap();
+        assert_eq!(&bytes[18..24], &[Opcode::JGT as u8, JumpMode::Absolute as u8, 0, 0, 0, 6]);
+        assert_eq!(&bytes[31..33], &[Opcode::CAL as u8, 1]);
+
+        let scripts = [Bytes::from(bytes), Bytes::from(&[Opcode::HLT as u8][..]), Bytes::from(&[Opcode::RET as u8][..])];
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(matches!(vm.run(1000), Ok(ExitStatus::Halted { .. })));
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut constants = HashMap::new();
+        constants.insert("SHIFT".to_string(), 4);
+        let bytes = parse("shl r0 SHIFT\nld r1.64 SHIFT\n").assemble(&HashMap::new(), &constants, 0).unwrap();
+        assert_eq!(bytes, vec![Opcode::SHL as u8, 0, 4, Opcode::LOD as u8, 1 << 6 | 1, 0, 0, 0, 0, 0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn test_label_errors() {
+        let errors = parse("a: nop\nb: jmp c\na: hlt\n").assemble(&HashMap::new(), &HashMap::new(), 0).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                Located { line: 3, at: 0, error: EncodeError::DuplicateLabel { name: "a".to_string(), line: 1 } },
+                Located { line: 2, at: 7, error: EncodeError::UndefinedLabel { name: "c".to_string() } },
+            ]
+        );
+    }
 }
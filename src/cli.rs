@@ -0,0 +1,282 @@
+extern crate bytes;
+
+use self::bytes::BytesMut;
+use container::Container;
+use disasm::disassemble;
+use frontend::{assemble_source, load, read, registers, DEFAULT_GAS};
+use repl;
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use verifier::verify;
+use vm::VmConfig;
+use vm_script::{ExitStatus, VMScript};
+
+pub const EXIT_OK: i32 = 0;
+/// The script raised a `VmError`
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_OUT_OF_GAS: i32 = 3;
+/// An input could not be read, assembled or decoded
+pub const EXIT_INPUT: i32 = 4;
+
+const USAGE: &str = "\
+usage: geodesic <command> [options]
+
+commands:
+  run [--gas N] FILE...  run the scripts, the first one being the entry script
+  asm [-o OUT] FILE      assemble FILE into a script container
+  disasm FILE            print the scripts in FILE as assembly
+  repl                   execute instructions interactively, see :help
+
+FILE may be `-` for standard input. Containers are recognised by their magic,
+`.gasm` files, `.asm` files and standard input are assembled, and any other
+file is a single raw script.
+";
+
+/// Runs the command line `args`, without the program name, and returns the exit code
+pub fn run(args: &[String], stdin: &mut dyn Read, out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    let code = match args.first().map(|a| a.as_str()) {
+        Some("run") => cmd_run(&args[1..], stdin, out, err),
+        Some("asm") => cmd_asm(&args[1..], stdin, out, err),
+        Some("disasm") => cmd_disasm(&args[1..], stdin, out, err),
+        Some("repl") if args.len() == 1 => repl::run(&mut BufReader::new(stdin), out)
+            .map(|_| EXIT_OK)
+            .map_err(|e| input(format!("cannot read input: {}", e))),
+        Some("-h") | Some("--help") | Some("help") => {
+            let _ = write!(out, "{}", USAGE);
+            Ok(EXIT_OK)
+        }
+        _ => Err((EXIT_USAGE, USAGE.to_string())),
+    };
+    match code {
+        Ok(code) => code,
+        Err((code, message)) => {
+            let _ = write!(err, "{}", message);
+            if !message.ends_with('\n') {
+                let _ = writeln!(err);
+            }
+            code
+        }
+    }
+}
+
+// An exit code and the message explaining it
+type Failure = (i32, String);
+
+fn usage(message: &str) -> Failure {
+    (EXIT_USAGE, format!("error: {}\n\n{}", message, USAGE))
+}
+
+fn input(message: String) -> Failure {
+    rejected(format!("error: {}", message))
+}
+
+// For the messages `frontend` returns, which are ready to print
+fn rejected(message: String) -> Failure {
+    (EXIT_INPUT, message)
+}
+
+fn cmd_run(
+    args: &[String],
+    stdin: &mut dyn Read,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> Result<i32, Failure> {
+    let mut gas = DEFAULT_GAS;
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gas" => {
+                let value = args.next().ok_or_else(|| usage("--gas needs a value"))?;
+                gas = value
+                    .parse()
+                    .map_err(|_| usage(&format!("invalid gas budget '{}'", value)))?;
+            }
+            _ => files.push(arg.as_str()),
+        }
+    }
+    if files.is_empty() {
+        return Err(usage("run needs at least one FILE"));
+    }
+    let mut scripts = vec![];
+    for file in files {
+        scripts.extend(load(file, stdin).map_err(rejected)?);
+    }
+    if scripts.is_empty() {
+        return Err(input("no scripts to run".to_string()));
+    }
+    // Reject malformed bytecode before any gas is spent
+    let diags = verify(&scripts);
+    if !diags.is_empty() {
+        let text: Vec<String> = diags.iter().map(|d| format!("error: {}", d)).collect();
+        return Err(rejected(text.join("\n")));
+    }
+
+    let mut heap = BytesMut::new();
+    let (code, regs) = {
+        let mut vm = VMScript::with_config(&scripts, &mut heap, VmConfig::default())
+            .map_err(|e| input(e.to_string()))?;
+        let result = vm.run(gas);
+        let code = match result {
+            Ok(ExitStatus::Halted { gas_used }) => {
+                let _ = writeln!(out, "halted, gas used {}", gas_used);
+                EXIT_OK
+            }
+            Ok(ExitStatus::OutOfGas { gas_used }) => {
+                let _ = writeln!(out, "out of gas, gas used {}", gas_used);
+                EXIT_OUT_OF_GAS
+            }
+            Err(e) => {
+                let _ = writeln!(out, "failed");
+                let _ = writeln!(err, "error: {}", e);
+                EXIT_FAILED
+            }
+        };
+        (code, vm.registers().clone())
+    };
+    for (reg, value) in registers(&regs) {
+        let _ = writeln!(out, "{} = {}", reg, value);
+    }
+    let bytes: Vec<String> = heap.iter().map(|b| format!("{:02x}", b)).collect();
+    let _ = writeln!(out, "heap ({} bytes): {}", heap.len(), bytes.join(" "));
+    Ok(code)
+}
+
+fn cmd_asm(
+    args: &[String],
+    stdin: &mut dyn Read,
+    out: &mut dyn Write,
+    _err: &mut dyn Write,
+) -> Result<i32, Failure> {
+    let mut output = None;
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or_else(|| usage("-o needs a file"))?),
+            _ if file.is_none() => file = Some(arg.as_str()),
+            _ => return Err(usage("asm takes a single FILE")),
+        }
+    }
+    let file = file.ok_or_else(|| usage("asm needs a FILE"))?;
+    let data = read(file, stdin).map_err(rejected)?;
+    let scripts = assemble_source(file, data).map_err(rejected)?;
+
+    let bytes = Container::new(scripts)
+        .to_bytes()
+        .map_err(|e| input(format!("{}: {}", file, e)))?;
+    let written = match output {
+        Some(path) if path != "-" => fs::write(path, &bytes),
+        _ => out.write_all(&bytes),
+    };
+    written.map_err(|e| input(format!("cannot write output: {}", e)))?;
+    Ok(EXIT_OK)
+}
+
+fn cmd_disasm(
+    args: &[String],
+    stdin: &mut dyn Read,
+    out: &mut dyn Write,
+    _err: &mut dyn Write,
+) -> Result<i32, Failure> {
+    let file = match args {
+        [file] => file,
+        _ => return Err(usage("disasm takes a single FILE")),
+    };
+    let scripts = load(file, stdin).map_err(rejected)?;
+    // Offsets go in comments so the output assembles back to the same scripts
+    for (index, script) in scripts.iter().enumerate() {
+        let lines =
+            disassemble(script).map_err(|e| input(format!("{}: script {}: {}", file, index, e)))?;
+        let _ = writeln!(out, ".script s{}", index);
+        for line in lines {
+            let _ = writeln!(out, "    {:<32} ; {:04x}", line.text, line.offset);
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use container::MAGIC;
+
+    fn call(args: &[&str], stdin: &[u8]) -> (i32, Vec<u8>, String) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (mut out, mut err) = (vec![], vec![]);
+        let code = run(&args, &mut &stdin[..], &mut out, &mut err);
+        (code, out, String::from_utf8(err).unwrap())
+    }
+
+    const SOURCE: &[u8] = b"ld r0 7\nld r1.64 -1\ncal lib\nhlt\n.script lib\npsh r0\nret\n";
+
+    #[test]
+    fn test_asm_and_run() {
+        let (code, container, _) = call(&["asm", "-"], SOURCE);
+        assert_eq!(code, EXIT_OK);
+        assert!(container.starts_with(&MAGIC));
+
+        let (code, out, _) = call(&["run", "-"], &container);
+        assert_eq!(code, EXIT_OK);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("halted, gas used "));
+        assert!(out.ends_with("r0.32 = 7\nr1.64 = -1\nheap (4 bytes): 07 00 00 00\n"));
+
+        // Running straight from source gives the same result
+        let (code, from_source, _) = call(&["run", "-"], SOURCE);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(String::from_utf8(from_source).unwrap(), out);
+    }
+
+    #[test]
+    fn test_disasm_round_trip() {
+        let (_, container, _) = call(&["asm", "-"], SOURCE);
+        let (code, text, _) = call(&["disasm", "-"], &container);
+        assert_eq!(code, EXIT_OK);
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with(".script s0\n    ld r0.32 7"));
+        assert!(text.contains("; 0006\n"));
+
+        let (_, again, _) = call(&["asm", "-"], text.as_bytes());
+        assert_eq!(again, container);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(call(&[], b"").0, EXIT_USAGE);
+        assert_eq!(call(&["frobnicate"], b"").0, EXIT_USAGE);
+        assert_eq!(call(&["run"], b"").0, EXIT_USAGE);
+        assert_eq!(call(&["run", "--gas", "lots", "-"], b"").0, EXIT_USAGE);
+        assert_eq!(call(&["disasm"], b"").0, EXIT_USAGE);
+        assert_eq!(call(&["--help"], b"").0, EXIT_OK);
+
+        let (code, _, err) = call(&["run", "-"], b"ld r0 0xZZ\n");
+        assert_eq!(code, EXIT_INPUT);
+        assert!(err.starts_with("<stdin>:1:7: expected immediate"));
+        assert_eq!(call(&["run", "/nonexistent/file.gasm"], b"").0, EXIT_INPUT);
+        assert_eq!(call(&["disasm", "-"], b"GDVM\0").0, EXIT_INPUT);
+
+        let (code, out, _) = call(
+            &["run", "--gas", "10", "-"],
+            b"loop: cmpi r0 0\njeq loop\nhlt\n",
+        );
+        assert_eq!(code, EXIT_OUT_OF_GAS);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("out of gas, gas used 10"));
+
+        // Scripts that fail verification never start
+        let (code, out, err) = call(&["run", "-"], b"cal 5\nnop\nhlt\n");
+        assert_eq!(code, EXIT_INPUT);
+        assert!(out.is_empty());
+        assert_eq!(
+            err,
+            "error: script 0 offset 0x0: cannot call lib with index 5\n"
+        );
+
+        let (code, _, err) = call(&["run", "-"], b"pop r0\nhlt\n");
+        assert_eq!(code, EXIT_FAILED);
+        assert!(err.starts_with("error: script 0 pc 0x0 (POP)"));
+    }
+}
@@ -0,0 +1,440 @@
+extern crate bytes;
+
+use self::bytes::Bytes;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Binary container for a compiled script library. All integers are big-endian.
+///
+/// ```text
+/// magic     "GDVM"
+/// version   u16
+/// count     u32                       number of scripts
+/// metadata  u16 entries, each a u16-length key and a u32-length value, UTF-8
+/// table     count * (u32 length, u64 FNV-1a hash of the script)
+/// scripts   the script bodies, concatenated in table order
+/// checksum  u32 CRC-32 of every preceding byte
+/// ```
+pub const MAGIC: [u8; 4] = *b"GDVM";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ContainerError {
+    BadMagic { found: Vec<u8> },
+    UnsupportedVersion { version: u16 },
+    // The file ended while reading `needed` bytes at `offset`, before the checksum
+    // could be read
+    Truncated { offset: usize, needed: usize },
+    InvalidMetadata { offset: usize },
+    ScriptHashMismatch { index: usize },
+    ChecksumMismatch { expected: u32, found: u32 },
+    TrailingBytes { offset: usize },
+    // A container must hold at least the entry script
+    NoScripts,
+    // `len` does not fit the width the format gives `field`
+    TooLarge { field: &'static str, len: usize },
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContainerError::BadMagic { ref found } => {
+                write!(f, "not a script container, magic is {:02x?}", found)
+            }
+            ContainerError::UnsupportedVersion { version } => {
+                write!(f, "unsupported container version {}", version)
+            }
+            ContainerError::Truncated { offset, needed } => write!(
+                f,
+                "truncated at offset {:#x}, {} more bytes expected",
+                offset, needed
+            ),
+            ContainerError::InvalidMetadata { offset } => {
+                write!(f, "metadata at offset {:#x} is not valid UTF-8", offset)
+            }
+            ContainerError::ScriptHashMismatch { index } => {
+                write!(f, "script {} does not match its hash", index)
+            }
+            ContainerError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum {:#010x} does not match contents ({:#010x})",
+                expected, found
+            ),
+            ContainerError::TrailingBytes { offset } => {
+                write!(
+                    f,
+                    "unexpected data after the last script at offset {:#x}",
+                    offset
+                )
+            }
+            ContainerError::NoScripts => write!(f, "the container has no scripts"),
+            ContainerError::TooLarge { field, len } => {
+                write!(f, "{} of {} is too large for the container", field, len)
+            }
+        }
+    }
+}
+
+impl Error for ContainerError {}
+
+/// Scripts in the order `VM::new` expects, with free-form metadata
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Container {
+    pub scripts: Vec<Bytes>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Container {
+    pub fn new(scripts: Vec<Bytes>) -> Container {
+        Container {
+            scripts,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Fails if there are no scripts or a length does not fit its field
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ContainerError> {
+        if self.scripts.is_empty() {
+            return Err(ContainerError::NoScripts);
+        }
+        let mut out = vec![];
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&VERSION.to_be_bytes());
+        out.extend_from_slice(&u32_len("script count", self.scripts.len())?.to_be_bytes());
+        out.extend_from_slice(&u16_len("metadata count", self.metadata.len())?.to_be_bytes());
+        for (key, value) in &self.metadata {
+            out.extend_from_slice(&u16_len("metadata key", key.len())?.to_be_bytes());
+            out.extend_from_slice(key.as_bytes());
+            out.extend_from_slice(&u32_len("metadata value", value.len())?.to_be_bytes());
+            out.extend_from_slice(value.as_bytes());
+        }
+        for script in &self.scripts {
+            out.extend_from_slice(&u32_len("script", script.len())?.to_be_bytes());
+            out.extend_from_slice(&fnv1a(script).to_be_bytes());
+        }
+        for script in &self.scripts {
+            out.extend_from_slice(script);
+        }
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let bytes = self
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        w.write_all(&bytes)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Container, ContainerError> {
+        let mut r = Reader { data, offset: 0 };
+        let magic = r.take(MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(ContainerError::BadMagic {
+                found: magic.to_vec(),
+            });
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(ContainerError::UnsupportedVersion { version });
+        }
+
+        // Check the whole file before trusting any count or length in it
+        let end = data.len().saturating_sub(4).max(r.offset);
+        let expected = Reader { data, offset: end }.u32()?;
+        let found = crc32(&data[..end]);
+        if expected != found {
+            return Err(ContainerError::ChecksumMismatch { expected, found });
+        }
+        r.data = &data[..end];
+
+        let count = r.u32()? as usize;
+        if count == 0 {
+            return Err(ContainerError::NoScripts);
+        }
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..r.u16()? {
+            let len = r.u16()? as usize;
+            let key = r.string(len)?;
+            let len = r.u32()? as usize;
+            let value = r.string(len)?;
+            metadata.insert(key, value);
+        }
+
+        // The count is untrusted, so let the reads bound the allocation
+        let mut table = vec![];
+        for _ in 0..count {
+            table.push((r.u32()? as usize, r.u64()?));
+        }
+        let mut scripts = Vec::with_capacity(table.len());
+        for (index, &(len, hash)) in table.iter().enumerate() {
+            let script = r.take(len)?;
+            if fnv1a(script) != hash {
+                return Err(ContainerError::ScriptHashMismatch { index });
+            }
+            scripts.push(Bytes::from(script));
+        }
+        if r.offset != end {
+            return Err(ContainerError::TrailingBytes { offset: r.offset });
+        }
+        Ok(Container { scripts, metadata })
+    }
+}
+
+fn u16_len(field: &'static str, len: usize) -> Result<u16, ContainerError> {
+    if len > u16::MAX as usize {
+        return Err(ContainerError::TooLarge { field, len });
+    }
+    Ok(len as u16)
+}
+
+fn u32_len(field: &'static str, len: usize) -> Result<u32, ContainerError> {
+    if len > u32::MAX as usize {
+        return Err(ContainerError::TooLarge { field, len });
+    }
+    Ok(len as u32)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ContainerError> {
+        let available = self.data.len() - self.offset;
+        if len > available {
+            return Err(ContainerError::Truncated {
+                offset: self.offset,
+                needed: len - available,
+            });
+        }
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, ContainerError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ContainerError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, ContainerError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, ContainerError> {
+        let offset = self.offset;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ContainerError::InvalidMetadata { offset })
+    }
+}
+
+/// 64-bit FNV-1a, used to tell which script is damaged. Not a cryptographic hash.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |c, _| {
+            if c & 1 == 1 {
+                (c >> 1) ^ 0xEDB8_8320
+            } else {
+                c >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruction::Opcode;
+    use vm::VM;
+
+    fn sample() -> Container {
+        let mut c = Container::new(vec![
+            Bytes::from(&[Opcode::CAL as u8, 0, Opcode::HLT as u8][..]),
+            Bytes::from(&[Opcode::NOP as u8, Opcode::RET as u8][..]),
+        ]);
+        c.metadata.insert("name".to_string(), "sample".to_string());
+        c
+    }
+
+    // Rewrites the trailing checksum after tampering with the contents
+    fn reseal(data: &mut Vec<u8>) {
+        let len = data.len() - 4;
+        let checksum = crc32(&data[..len]);
+        data.truncate(len);
+        data.extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let c = sample();
+        let data = c.to_bytes().unwrap();
+        assert_eq!(&data[..4], b"GDVM");
+        let read = Container::from_bytes(&data).unwrap();
+        assert_eq!(read, c);
+
+        let mut written = vec![];
+        c.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+
+        let mut vm = VM::new(&read.scripts).unwrap();
+        assert!(vm.run(100).is_ok());
+    }
+
+    #[test]
+    fn test_write_errors() {
+        assert_eq!(
+            Container::default().to_bytes(),
+            Err(ContainerError::NoScripts)
+        );
+        let mut written = vec![];
+        let e = Container::default().write_to(&mut written).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(written.is_empty());
+
+        let mut c = sample();
+        c.metadata.insert("k".repeat(0x1_0000), String::new());
+        assert_eq!(
+            c.to_bytes(),
+            Err(ContainerError::TooLarge {
+                field: "metadata key",
+                len: 0x1_0000
+            })
+        );
+        c.metadata.clear();
+        c.metadata.insert("k".repeat(0xFFFF), String::new());
+        assert!(Container::from_bytes(&c.to_bytes().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let data = sample().to_bytes().unwrap();
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::BadMagic {
+                found: b"XDVM".to_vec()
+            })
+        );
+
+        let mut bad = data.clone();
+        bad[5] = 2;
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::UnsupportedVersion { version: 2 })
+        );
+
+        // Any damaged byte is caught by the checksum before it is parsed, and a
+        // damaged script that was resealed by its hash
+        let mut bad = data.clone();
+        let script = data.len() - 4 - 2;
+        bad[script] ^= 0xFF;
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::ChecksumMismatch { .. })
+        ));
+        reseal(&mut bad);
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::ScriptHashMismatch { index: 1 })
+        );
+        let mut bad = data.clone();
+        bad[14] ^= 0x20;
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::ChecksumMismatch { .. })
+        ));
+        for count in 6..10 {
+            let mut bad = data.clone();
+            bad[count] ^= 0x01;
+            assert!(matches!(
+                Container::from_bytes(&bad),
+                Err(ContainerError::ChecksumMismatch { .. })
+            ));
+        }
+
+        let mut bad = data.clone();
+        bad.push(0);
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::ChecksumMismatch { .. })
+        ));
+        reseal(&mut bad);
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::TrailingBytes {
+                offset: data.len() - 4
+            })
+        );
+
+        let mut bad = data.clone();
+        bad[14] = 0xFF;
+        reseal(&mut bad);
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::InvalidMetadata { offset: 14 })
+        );
+
+        let mut bad = data.clone();
+        bad[6..10].copy_from_slice(&0u32.to_be_bytes());
+        reseal(&mut bad);
+        assert_eq!(Container::from_bytes(&bad), Err(ContainerError::NoScripts));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = sample().to_bytes().unwrap();
+        // Too short for the magic, version and checksum, or failing the checksum otherwise
+        for len in 0..data.len() {
+            match Container::from_bytes(&data[..len]) {
+                Err(ContainerError::Truncated { offset, needed }) => {
+                    assert!(len < 6 + 4 && offset <= len && needed > 0)
+                }
+                Err(ContainerError::ChecksumMismatch { .. }) => assert!(len >= 6 + 4),
+                other => panic!("length {}: {:?}", len, other),
+            }
+        }
+
+        // A huge script count with a valid checksum fails on the first table entry
+        let mut bad = MAGIC.to_vec();
+        bad.extend_from_slice(&VERSION.to_be_bytes());
+        bad.extend_from_slice(&u32::MAX.to_be_bytes());
+        bad.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        reseal(&mut bad);
+        assert_eq!(
+            Container::from_bytes(&bad),
+            Err(ContainerError::Truncated {
+                offset: 12,
+                needed: 4
+            })
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeSet;
+use vm_error::VmError;
+use vm_script::{ExitStatus, Step, VMScript};
+
+/// Why `Debugger::step_over` or `Debugger::resume` handed control back
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stop {
+    // The step finished without reaching a breakpoint or exiting
+    Stepped,
+    // The next instruction is at a breakpoint and has not run
+    Breakpoint { script: usize, pc: usize },
+    Exited(ExitStatus),
+}
+
+/// Drives a `VMScript` one instruction at a time, stopping at breakpoints.
+/// Registers, flags and the heap are reached through `vm` and `vm_mut`.
+pub struct Debugger<'a> {
+    vm: VMScript<'a>,
+    // (script index, pc) pairs
+    breakpoints: BTreeSet<(usize, usize)>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: VMScript<'a>) -> Debugger<'a> {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &VMScript<'a> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VMScript<'a> {
+        &mut self.vm
+    }
+
+    pub fn into_inner(self) -> VMScript<'a> {
+        self.vm
+    }
+
+    /// Stops before the instruction at `pc` in script `script` runs. Returns false if
+    /// the breakpoint was already set.
+    pub fn add_breakpoint(&mut self, script: usize, pc: usize) -> bool {
+        self.breakpoints.insert((script, pc))
+    }
+
+    pub fn remove_breakpoint(&mut self, script: usize, pc: usize) -> bool {
+        self.breakpoints.remove(&(script, pc))
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &(usize, usize)> {
+        self.breakpoints.iter()
+    }
+
+    /// Executes the next instruction, entering the called script on a CAL
+    pub fn step_into(&mut self) -> Result<Step, VmError> {
+        self.vm.step()
+    }
+
+    /// Executes the next instruction. A CAL runs until the callee returns, unless it
+    /// halts or reaches a breakpoint first.
+    pub fn step_over(&mut self) -> Result<Stop, VmError> {
+        let depth = self.vm.calls().len();
+        let step = self.vm.step()?;
+        if let Some(status) = step.status {
+            return Ok(Stop::Exited(status));
+        }
+        if self.vm.calls().len() <= depth {
+            return Ok(Stop::Stepped);
+        }
+        self.run_while(|vm| vm.calls().len() > depth)
+    }
+
+    /// Runs until a breakpoint or the end of execution. The instruction at the current
+    /// pc always runs, so resuming from a breakpoint moves past it.
+    pub fn resume(&mut self) -> Result<Stop, VmError> {
+        let step = self.vm.step()?;
+        if let Some(status) = step.status {
+            return Ok(Stop::Exited(status));
+        }
+        self.run_while(|_| true)
+    }
+
+    fn run_while<F: Fn(&VMScript) -> bool>(&mut self, keep_going: F) -> Result<Stop, VmError> {
+        while keep_going(&self.vm) {
+            let at = (self.vm.script_index(), self.vm.pc());
+            if self.breakpoints.contains(&at) {
+                return Ok(Stop::Breakpoint {
+                    script: at.0,
+                    pc: at.1,
+                });
+            }
+            if let Some(status) = self.vm.step()?.status {
+                return Ok(Stop::Exited(status));
+            }
+        }
+        Ok(Stop::Stepped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate bytes;
+
+    use self::bytes::{Bytes, BytesMut};
+    use super::*;
+    use asm::assembler::assemble;
+    use decode::Arg;
+    use instruction::Opcode;
+    use vm_script::{Flags, HeapAccess};
+
+    const SOURCE: &str = "\
+ld r0 7
+psh r0
+cal inc
+ld r1.64 -1
+hlt
+.script inc
+inc r0
+pop r2
+ret
+";
+
+    #[test]
+    fn test_step() {
+        let scripts = assemble("test", SOURCE).unwrap();
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&scripts, &mut heap).unwrap();
+
+        let step = vm.step().unwrap();
+        assert_eq!(step.script, 0);
+        assert_eq!(step.instruction.opcode, Opcode::LOD);
+        assert_eq!(step.instruction.args, vec![Arg::Reg(0), Arg::Imm(7)]);
+        assert_eq!(step.written, vec![0]);
+        assert_eq!(step.status, None);
+
+        let step = vm.step().unwrap();
+        assert!(step.written.is_empty());
+        assert_eq!(
+            step.heap,
+            Some(HeapAccess::Push {
+                offset: 0,
+                bytes: vec![7, 0, 0, 0]
+            })
+        );
+
+        let step = vm.step().unwrap();
+        assert_eq!(step.instruction.opcode, Opcode::CAL);
+        assert_eq!((vm.script_index(), vm.pc()), (1, 0));
+        assert_eq!(vm.step().unwrap().written, vec![0]);
+        let step = vm.step().unwrap();
+        assert_eq!(step.written, vec![2]);
+        assert_eq!(
+            step.heap,
+            Some(HeapAccess::Pop {
+                offset: 0,
+                bytes: vec![7, 0, 0, 0]
+            })
+        );
+        vm.step().unwrap();
+        assert_eq!(vm.step().unwrap().written, vec![1 << 6 | 1]);
+        let step = vm.step().unwrap();
+        assert_eq!(step.instruction.opcode, Opcode::HLT);
+        assert!(matches!(step.status, Some(ExitStatus::Halted { .. })));
+        assert_eq!(vm.regs32()[0], 8);
+        assert_eq!(vm.regs64()[1], -1);
+    }
+
+    #[test]
+    fn test_step_out_of_gas() {
+        let scripts = [Bytes::from(
+            &[Opcode::INC as u8, 0, Opcode::ADD as u8, 0][..],
+        )];
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&scripts, &mut heap).unwrap();
+        vm.set_gas(0);
+        let step = vm.step().unwrap();
+        assert_eq!(step.status, Some(ExitStatus::OutOfGas { gas_used: 0 }));
+        assert!(step.written.is_empty());
+        assert_eq!((vm.pc(), vm.regs32()[0]), (0, 0));
+
+        // Out of gas on an instruction that cannot be read reports why it cannot
+        vm.set_gas(u64::MAX);
+        vm.step().unwrap();
+        vm.set_gas(0);
+        assert_eq!(
+            vm.step(),
+            Err(VmError::TruncatedOperand {
+                script: 0,
+                pc: 2,
+                opcode: Opcode::ADD as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let scripts = assemble("test", SOURCE).unwrap();
+        let mut heap = BytesMut::new();
+        let mut dbg = Debugger::new(VMScript::new(&scripts, &mut heap).unwrap());
+        assert!(dbg.add_breakpoint(1, 2));
+        assert!(!dbg.add_breakpoint(1, 2));
+        assert!(dbg.add_breakpoint(0, 0));
+
+        // The breakpoint under the pc does not stop a resume
+        assert_eq!(dbg.resume(), Ok(Stop::Breakpoint { script: 1, pc: 2 }));
+        assert_eq!(dbg.vm().regs32()[0], 8);
+        assert_eq!(dbg.vm().calls().len(), 1);
+
+        assert!(dbg.remove_breakpoint(1, 2));
+        assert_eq!(dbg.breakpoints().count(), 1);
+        assert!(matches!(
+            dbg.resume(),
+            Ok(Stop::Exited(ExitStatus::Halted { .. }))
+        ));
+        assert_eq!(dbg.vm().regs32()[2], 7);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let scripts = assemble("test", SOURCE).unwrap();
+        let mut heap = BytesMut::new();
+        let mut dbg = Debugger::new(VMScript::new(&scripts, &mut heap).unwrap());
+        assert_eq!(dbg.step_over(), Ok(Stop::Stepped));
+        assert_eq!(dbg.step_over(), Ok(Stop::Stepped));
+        assert_eq!(dbg.step_over(), Ok(Stop::Stepped));
+        assert_eq!((dbg.vm().script_index(), dbg.vm().calls().len()), (0, 0));
+        assert_eq!(dbg.vm().regs32()[2], 7);
+
+        // A breakpoint in the callee interrupts stepping over the CAL
+        dbg.vm_mut().reset();
+        dbg.add_breakpoint(1, 2);
+        dbg.step_over().unwrap();
+        dbg.step_over().unwrap();
+        assert_eq!(dbg.step_over(), Ok(Stop::Breakpoint { script: 1, pc: 2 }));
+        assert_eq!(dbg.step_over(), Ok(Stop::Stepped));
+        assert_eq!((dbg.vm().script_index(), dbg.vm().pc()), (1, 4));
+        assert_eq!(dbg.step_over(), Ok(Stop::Stepped));
+        assert_eq!(dbg.vm().script_index(), 0);
+    }
+
+    #[test]
+    fn test_edit_state() {
+        let scripts =
+            assemble("test", "cmp r3.128 r4.128\njeq done\nld r0 1\ndone: hlt\n").unwrap();
+        let mut heap = BytesMut::new();
+        let mut dbg = Debugger::new(VMScript::new(&scripts, &mut heap).unwrap());
+        dbg.vm_mut().regs128_mut()[3] = 5;
+        dbg.step_into().unwrap();
+        assert_eq!(
+            dbg.vm().flags(),
+            Flags {
+                eq: false,
+                lt: false,
+                gt: true,
+                of: false
+            }
+        );
+        // Forcing the branch skips the load
+        dbg.vm_mut().set_flags(Flags {
+            eq: true,
+            ..Flags::default()
+        });
+        dbg.vm_mut().regs32_mut()[1] = 9;
+        dbg.vm_mut().regs64_mut()[1] = -9;
+        assert!(matches!(dbg.resume(), Ok(Stop::Exited(_))));
+        let vm = dbg.into_inner();
+        assert_eq!((vm.regs32()[0], vm.regs32()[1], vm.regs64()[1]), (0, 9, -9));
+    }
+}
@@ -0,0 +1,229 @@
+use instruction::{JumpMode, Opcode, Operand, RegLocal};
+use std::error::Error;
+use std::fmt;
+
+/// One operand as it was read from the byte stream
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arg {
+    Reg(u8),   // Raw register byte, the bank is in the top two bits
+    Imm(i128), // Sign-extended from the width of the preceding register
+    Byte(u8),
+    Target { mode: JumpMode, value: i64 },
+}
+
+/// A single decoded instruction with the operand layout `VMScript::step` expects
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub args: Vec<Arg>,
+    pub len: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    UnknownOpcode { offset: usize, opcode: u8 },
+    Truncated { offset: usize },
+    // Only raised when the register decides the width of an immediate
+    InvalidRegister { offset: usize, reg: u8 },
+    InvalidJumpMode { offset: usize, mode: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownOpcode { offset, opcode } => {
+                write!(f, "offset {:#x}: unknown opcode {:#x}", offset, opcode)
+            }
+            DecodeError::Truncated { offset } => {
+                write!(
+                    f,
+                    "offset {:#x}: script ends inside the instruction",
+                    offset
+                )
+            }
+            DecodeError::InvalidRegister { offset, reg } => {
+                write!(f, "offset {:#x}: invalid register {:#x}", offset, reg)
+            }
+            DecodeError::InvalidJumpMode { offset, mode } => {
+                write!(f, "offset {:#x}: invalid jump mode {:#x}", offset, mode)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instruction {
+    /// Offset a jump lands on within its script, which may be out of range
+    pub fn jump_target(&self) -> Option<i64> {
+        match self.args.first() {
+            Some(&Arg::Target {
+                mode: JumpMode::Absolute,
+                value,
+            }) => Some(value),
+            Some(&Arg::Target {
+                mode: JumpMode::Relative,
+                value,
+            }) => Some(self.offset as i64 + value),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the instruction starting at `offset`
+pub fn decode(script: &[u8], offset: usize) -> Result<Instruction, DecodeError> {
+    let op = match script.get(offset) {
+        Some(op) => *op,
+        None => return Err(DecodeError::Truncated { offset }),
+    };
+    let opcode = Opcode::from(op);
+    if opcode == Opcode::ERR {
+        return Err(DecodeError::UnknownOpcode { offset, opcode: op });
+    }
+    let mut pc = offset + 1;
+    let mut args = vec![];
+    let mut last_reg = 0;
+    for operand in opcode.operands() {
+        let arg = match *operand {
+            Operand::Reg => {
+                last_reg = take(script, offset, &mut pc, 1)?[0];
+                Arg::Reg(last_reg)
+            }
+            Operand::Imm => {
+                let width = match RegLocal::decode(last_reg) {
+                    Some(r) => r,
+                    None => {
+                        return Err(DecodeError::InvalidRegister {
+                            offset,
+                            reg: last_reg,
+                        })
+                    }
+                };
+                let val = read_be(take(script, offset, &mut pc, width.size())?);
+                Arg::Imm(match width {
+                    RegLocal::REG32 => i128::from(val as u32 as i32),
+                    RegLocal::REG64 => i128::from(val as u64 as i64),
+                    RegLocal::REG128 => val as i128,
+                })
+            }
+            Operand::Byte => Arg::Byte(take(script, offset, &mut pc, 1)?[0]),
+            Operand::Target => {
+                let mode = take(script, offset, &mut pc, 1)?[0];
+                let val = read_be(take(script, offset, &mut pc, 4)?) as u32;
+                match JumpMode::decode(mode) {
+                    Some(JumpMode::Absolute) => Arg::Target {
+                        mode: JumpMode::Absolute,
+                        value: i64::from(val),
+                    },
+                    Some(JumpMode::Relative) => Arg::Target {
+                        mode: JumpMode::Relative,
+                        value: i64::from(val as i32),
+                    },
+                    None => return Err(DecodeError::InvalidJumpMode { offset, mode }),
+                }
+            }
+        };
+        args.push(arg);
+    }
+    Ok(Instruction {
+        offset,
+        opcode,
+        args,
+        len: pc - offset,
+    })
+}
+
+fn take<'a>(
+    script: &'a [u8],
+    offset: usize,
+    pc: &mut usize,
+    n: usize,
+) -> Result<&'a [u8], DecodeError> {
+    if *pc + n > script.len() {
+        return Err(DecodeError::Truncated { offset });
+    }
+    *pc += n;
+    Ok(&script[*pc - n..*pc])
+}
+
+fn read_be(b: &[u8]) -> u128 {
+    b.iter().fold(0, |acc, v| (acc << 8) | u128::from(*v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let script = [
+            Opcode::LOD as u8,
+            1 << 6,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFE,
+            0,
+        ];
+        let inst = decode(&script, 0).unwrap();
+        assert_eq!(inst.opcode, Opcode::LOD);
+        assert_eq!(inst.args, vec![Arg::Reg(1 << 6), Arg::Imm(-2)]);
+        assert_eq!(inst.len, 10);
+        assert_eq!(decode(&script, 10).unwrap().opcode, Opcode::HLT);
+    }
+
+    #[test]
+    fn test_decode_jump_target() {
+        let script = [
+            Opcode::NOP as u8,
+            Opcode::JNE as u8,
+            1,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+        ];
+        let inst = decode(&script, 1).unwrap();
+        assert_eq!(inst.jump_target(), Some(0));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            decode(&[0xFE], 0),
+            Err(DecodeError::UnknownOpcode {
+                offset: 0,
+                opcode: 0xFE
+            })
+        );
+        assert_eq!(
+            decode(&[Opcode::ADD as u8, 0], 0),
+            Err(DecodeError::Truncated { offset: 0 })
+        );
+        assert_eq!(
+            decode(&[Opcode::LOD as u8, 0xC0, 0, 0, 0, 0], 0),
+            Err(DecodeError::InvalidRegister {
+                offset: 0,
+                reg: 0xC0
+            })
+        );
+        // Registers that do not size an immediate are left for the caller to check
+        assert!(decode(&[Opcode::INC as u8, 0xC0], 0).is_ok());
+    }
+
+    #[test]
+    fn test_decode_error_display() {
+        let err = decode(&[Opcode::NOP as u8, Opcode::ADD as u8, 0], 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "offset 0x1: script ends inside the instruction"
+        );
+        let err = decode(&[0xFE], 0).unwrap_err();
+        assert_eq!(err.to_string(), "offset 0x0: unknown opcode 0xfe");
+    }
+}
@@ -0,0 +1,166 @@
+extern crate bytes;
+
+use self::bytes::Bytes;
+use decode::{decode, Arg, DecodeError, Instruction};
+use instruction::{JumpMode, RegLocal};
+use std::fmt;
+
+/// One disassembled instruction. `text` is valid input for the assembler,
+/// displaying the line prefixes it with the instruction's offset.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisasmLine {
+    pub offset: usize,
+    pub text: String,
+}
+
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}: {}", self.offset, self.text)
+    }
+}
+
+/// Decodes a script into one line of assembly per instruction
+pub fn disassemble(script: &Bytes) -> Result<Vec<DisasmLine>, DecodeError> {
+    let mut lines = vec![];
+    let mut pc = 0;
+    while pc < script.len() {
+        let inst = decode(script, pc)?;
+        lines.push(DisasmLine {
+            offset: pc,
+            text: format_instruction(&inst)?,
+        });
+        pc += inst.len;
+    }
+    Ok(lines)
+}
+
+/// Assembly source for a script, without offsets
+pub fn source(script: &Bytes) -> Result<String, DecodeError> {
+    let mut out = String::new();
+    for line in disassemble(script)? {
+        out.push_str(&line.text);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Renders a decoded instruction the way the assembler reads it
+pub fn format_instruction(inst: &Instruction) -> Result<String, DecodeError> {
+    let mut line = inst.opcode.mnemonic().to_string();
+    for arg in &inst.args {
+        let text = match *arg {
+            Arg::Reg(reg) => match format_reg(reg) {
+                Some(text) => text,
+                None => {
+                    return Err(DecodeError::InvalidRegister {
+                        offset: inst.offset,
+                        reg,
+                    })
+                }
+            },
+            Arg::Imm(val) => format!("{}", val),
+            Arg::Byte(b) => format!("{}", b),
+            Arg::Target {
+                mode: JumpMode::Absolute,
+                value,
+            } => format!("{}", value),
+            Arg::Target {
+                mode: JumpMode::Relative,
+                value,
+            } => format!("{:+}", value),
+        };
+        line.push(' ');
+        line.push_str(&text);
+    }
+    Ok(line)
+}
+
+/// Register name with its bank, e.g. `r5.64`, or `None` if the top bits select no bank
+pub fn format_reg(reg: u8) -> Option<String> {
+    RegLocal::decode(reg).map(|r| format!("r{}.{}", reg & 0x3F, r.size() * 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assembler::assemble;
+    use instruction::Opcode;
+
+    #[test]
+    fn test_disassemble_jumps() {
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                0,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                Opcode::CMP as u8,
+                0,
+                1,
+                Opcode::JNE as u8,
+                JumpMode::Relative as u8,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFD,
+                Opcode::JMP as u8,
+                JumpMode::Absolute as u8,
+                0,
+                0,
+                0,
+                20,
+                0,
+            ][..],
+        );
+        assert_eq!(
+            source(&script),
+            Ok("ld r0.32 -1\ncmp r0.32 r1.32\njne -3\njmp 20\nhlt\n".to_string())
+        );
+        let lines = disassemble(&script).unwrap();
+        assert_eq!(lines[2].offset, 9);
+        assert_eq!(lines[2].to_string(), "0009: jne -3");
+    }
+
+    #[test]
+    fn test_disassemble_widths() {
+        let mut script = vec![Opcode::LOD as u8, (2 << 6) + 3];
+        script.extend_from_slice(&(-5i128).to_be_bytes());
+        script.extend_from_slice(&[Opcode::SEXT as u8, (1 << 6) + 62, 7, Opcode::CAL as u8, 2]);
+        assert_eq!(
+            source(&Bytes::from(script)),
+            Ok("ld r3.128 -5\nsext r62.64 r7.32\ncal 2\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_disassemble_invalid_register() {
+        let script = Bytes::from(&[Opcode::NOP as u8, Opcode::INC as u8, 0xC0][..]);
+        assert_eq!(
+            disassemble(&script),
+            Err(DecodeError::InvalidRegister {
+                offset: 1,
+                reg: 0xC0
+            })
+        );
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let source = "ld r0.32 -1\nld r3.128 170141183460469231731687303715884105727\n\
+                      subi r9.64 -9223372036854775808\nsext r1.64 r0.32\nshru r2.32 31\n\
+                      psh r3.128\ncal 2\njmp 12\njle -6\njge +6\nret\nhlt\n";
+        let scripts = assemble("test", source).unwrap();
+        assert_eq!(super::source(&scripts[0]), Ok(source.to_string()));
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        let script = Bytes::from(&[Opcode::NOP as u8, Opcode::JMP as u8, 0, 0][..]);
+        assert_eq!(
+            disassemble(&script),
+            Err(DecodeError::Truncated { offset: 1 })
+        );
+    }
+}
@@ -0,0 +1,67 @@
+extern crate bytes;
+
+use self::bytes::Bytes;
+use asm::assembler::assemble;
+use container::{Container, MAGIC};
+use disasm::format_reg;
+use std::fs;
+use std::io::Read;
+use vm_script::{Registers, REGSIZE};
+
+/// Gas for `geodesic run` without `--gas`, and for each REPL line
+pub const DEFAULT_GAS: u64 = 1_000_000;
+
+/// Contents of `file`, or of `stdin` when it is `-`. Errors are the message to print.
+pub fn read(file: &str, stdin: &mut dyn Read) -> Result<Vec<u8>, String> {
+    let mut data = vec![];
+    let result = if file == "-" {
+        stdin.read_to_end(&mut data).map(|_| ())
+    } else {
+        fs::read(file).map(|d| data = d)
+    };
+    result.map_err(|e| format!("error: cannot read {}: {}", file, e))?;
+    Ok(data)
+}
+
+fn is_source(file: &str) -> bool {
+    file == "-" || file.ends_with(".gasm") || file.ends_with(".asm")
+}
+
+/// Scripts from a container, an assembly source or a raw script
+pub fn load(file: &str, stdin: &mut dyn Read) -> Result<Vec<Bytes>, String> {
+    let data = read(file, stdin)?;
+    if data.starts_with(&MAGIC) {
+        return Container::from_bytes(&data)
+            .map(|c| c.scripts)
+            .map_err(|e| format!("error: {}: {}", file, e));
+    }
+    if !is_source(file) {
+        return Ok(vec![Bytes::from(data)]);
+    }
+    assemble_source(file, data)
+}
+
+/// Scripts assembled from `data`, read from `file`
+pub fn assemble_source(file: &str, data: Vec<u8>) -> Result<Vec<Bytes>, String> {
+    let source = String::from_utf8(data).map_err(|_| format!("error: {} is not UTF-8", file))?;
+    let name = if file == "-" { "<stdin>" } else { file };
+    assemble(name, &source).map_err(|errors| {
+        let text: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        text.join("\n\n")
+    })
+}
+
+/// Every non-zero register, by bank then index
+pub fn registers(r: &Registers) -> Vec<(String, i128)> {
+    let mut regs = vec![];
+    for bank in 0..3u8 {
+        for idx in 0..REGSIZE as u8 {
+            let reg = bank << 6 | idx;
+            match r.get(reg) {
+                Some(0) | None => {}
+                Some(value) => regs.push((format_reg(reg).unwrap(), value)),
+            }
+        }
+    }
+    regs
+}
@@ -0,0 +1,76 @@
+use instruction::{Opcode, RegLocal};
+
+// Base costs, for 32-bit operands. Register operands scale these by
+// the number of 32-bit words they hold.
+const ALU: u64 = 1;
+const MUL: u64 = 3;
+const DIV: u64 = 5;
+const JUMP: u64 = 2;
+const CALL: u64 = 10;
+const RET: u64 = 2;
+// PSH and POP are charged per byte moved on top of this
+const HEAP: u64 = 2;
+
+/// Gas charged for executing `op`. `width` is the bank of the first
+/// register operand, or `None` if the opcode does not start with one.
+/// SEXT, ZEXT and TRUNC are charged for the wider of their two registers,
+/// so the caller passes that bank for them.
+pub fn cost(op: Opcode, width: Option<RegLocal>) -> u64 {
+    let bytes = width.map_or(4, |w| w.size()) as u64;
+    let words = bytes / 4;
+    match op {
+        Opcode::HLT | Opcode::ERR => 0,
+        Opcode::NOP => 1,
+        // Immediates are charged for the bytes they occupy like LOD
+        Opcode::LOD => ALU + words,
+        Opcode::ADDI
+        | Opcode::SUBI
+        | Opcode::ANDI
+        | Opcode::ORI
+        | Opcode::XORI
+        | Opcode::CMPI => ALU * words + words,
+        Opcode::MULI => MUL * words + words,
+        Opcode::INC
+        | Opcode::ADD
+        | Opcode::SUB
+        | Opcode::SHR
+        | Opcode::SHRU
+        | Opcode::SHL
+        | Opcode::AND
+        | Opcode::OR
+        | Opcode::NOT
+        | Opcode::XOR
+        | Opcode::CMP
+        | Opcode::CMPU
+        | Opcode::REM
+        | Opcode::MOV
+        | Opcode::SEXT
+        | Opcode::ZEXT
+        | Opcode::TRUNC => ALU * words,
+        Opcode::MUL => MUL * words,
+        Opcode::DIV | Opcode::MOD | Opcode::DIVU | Opcode::MODU => DIV * words,
+        Opcode::CAL => CALL,
+        Opcode::RET => RET,
+        Opcode::PSH | Opcode::POP => HEAP + bytes,
+        Opcode::JMP
+        | Opcode::JEQ
+        | Opcode::JNE
+        | Opcode::JLT
+        | Opcode::JGT
+        | Opcode::JLE
+        | Opcode::JGE => JUMP,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wide_ops_cost_more() {
+        assert!(cost(Opcode::MUL, Some(RegLocal::REG128)) > cost(Opcode::ADD, Some(RegLocal::REG32)));
+        assert!(cost(Opcode::DIV, Some(RegLocal::REG128)) > cost(Opcode::DIV, Some(RegLocal::REG64)));
+        assert!(cost(Opcode::PSH, Some(RegLocal::REG64)) > cost(Opcode::PSH, Some(RegLocal::REG32)));
+        assert_eq!(cost(Opcode::PSH, Some(RegLocal::REG128)), HEAP + 16);
+    }
+}
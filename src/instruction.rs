@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode{
 	HLT,
 	NOP,
@@ -19,6 +19,30 @@ pub enum Opcode{
 	CMP,
 	PSH,
 	POP,
+	JMP,
+	JEQ,
+	JNE,
+	JLT,
+	JGT,
+	JLE,
+	JGE,
+	RET,
+	REM,
+	MOV,
+	SEXT,
+	ZEXT,
+	TRUNC,
+	ADDI,
+	SUBI,
+	MULI,
+	ANDI,
+	ORI,
+	XORI,
+	CMPI,
+	CMPU,
+	DIVU,
+	MODU,
+	SHRU,
 	ERR,
 }
 
@@ -44,7 +68,184 @@ impl From<u8> for Opcode {
 			0x10 => Opcode::CMP,
 			0x11 => Opcode::PSH,
 			0x12 => Opcode::POP,
+			0x13 => Opcode::JMP,
+			0x14 => Opcode::JEQ,
+			0x15 => Opcode::JNE,
+			0x16 => Opcode::JLT,
+			0x17 => Opcode::JGT,
+			0x18 => Opcode::JLE,
+			0x19 => Opcode::JGE,
+			0x1A => Opcode::RET,
+			0x1B => Opcode::REM,
+			0x1C => Opcode::MOV,
+			0x1D => Opcode::SEXT,
+			0x1E => Opcode::ZEXT,
+			0x1F => Opcode::TRUNC,
+			0x20 => Opcode::ADDI,
+			0x21 => Opcode::SUBI,
+			0x22 => Opcode::MULI,
+			0x23 => Opcode::ANDI,
+			0x24 => Opcode::ORI,
+			0x25 => Opcode::XORI,
+			0x26 => Opcode::CMPI,
+			0x27 => Opcode::CMPU,
+			0x28 => Opcode::DIVU,
+			0x29 => Opcode::MODU,
+			0x2A => Opcode::SHRU,
 			_=> Opcode::ERR
 		}
 	}
 }
+
+/// Shape of a single operand as it appears in the byte stream after the opcode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+	Reg,    // One register byte, see `RegLocal`
+	Imm,    // Big-endian immediate as wide as the preceding register
+	Byte,   // Raw u8, a shift amount or a CAL index
+	Target, // `JumpMode` byte followed by a big-endian 32-bit target
+}
+
+impl Opcode {
+	/// Operands decoded by `VMScript::step` for this opcode, in order
+	pub fn operands(self) -> &'static [Operand] {
+		match self {
+			Opcode::HLT | Opcode::NOP | Opcode::RET | Opcode::ERR => &[],
+			Opcode::LOD
+			| Opcode::ADDI
+			| Opcode::SUBI
+			| Opcode::MULI
+			| Opcode::ANDI
+			| Opcode::ORI
+			| Opcode::XORI
+			| Opcode::CMPI => &[Operand::Reg, Operand::Imm],
+			Opcode::INC | Opcode::NOT | Opcode::PSH | Opcode::POP | Opcode::REM => &[Operand::Reg],
+			Opcode::ADD
+			| Opcode::SUB
+			| Opcode::MUL
+			| Opcode::DIV
+			| Opcode::MOD
+			| Opcode::AND
+			| Opcode::OR
+			| Opcode::XOR
+			| Opcode::CMP
+			| Opcode::CMPU
+			| Opcode::DIVU
+			| Opcode::MODU
+			| Opcode::MOV
+			| Opcode::SEXT
+			| Opcode::ZEXT
+			| Opcode::TRUNC => &[Operand::Reg, Operand::Reg],
+			Opcode::SHR | Opcode::SHL | Opcode::SHRU => &[Operand::Reg, Operand::Byte],
+			Opcode::CAL => &[Operand::Byte],
+			Opcode::JMP
+			| Opcode::JEQ
+			| Opcode::JNE
+			| Opcode::JLT
+			| Opcode::JGT
+			| Opcode::JLE
+			| Opcode::JGE => &[Operand::Target],
+		}
+	}
+
+	/// Name used for this opcode in assembly text
+	pub fn mnemonic(self) -> &'static str {
+		match self {
+			Opcode::HLT => "hlt",
+			Opcode::NOP => "nop",
+			Opcode::LOD => "ld",
+			Opcode::INC => "inc",
+			Opcode::ADD => "add",
+			Opcode::SUB => "sub",
+			Opcode::MUL => "mul",
+			Opcode::DIV => "div",
+			Opcode::MOD => "mod",
+			Opcode::SHR => "shr",
+			Opcode::SHL => "shl",
+			Opcode::AND => "and",
+			Opcode::OR => "or",
+			Opcode::NOT => "not",
+			Opcode::XOR => "xor",
+			Opcode::CAL => "cal",
+			Opcode::CMP => "cmp",
+			Opcode::PSH => "psh",
+			Opcode::POP => "pop",
+			Opcode::JMP => "jmp",
+			Opcode::JEQ => "jeq",
+			Opcode::JNE => "jne",
+			Opcode::JLT => "jlt",
+			Opcode::JGT => "jgt",
+			Opcode::JLE => "jle",
+			Opcode::JGE => "jge",
+			Opcode::RET => "ret",
+			Opcode::REM => "rem",
+			Opcode::MOV => "mov",
+			Opcode::SEXT => "sext",
+			Opcode::ZEXT => "zext",
+			Opcode::TRUNC => "trunc",
+			Opcode::ADDI => "addi",
+			Opcode::SUBI => "subi",
+			Opcode::MULI => "muli",
+			Opcode::ANDI => "andi",
+			Opcode::ORI => "ori",
+			Opcode::XORI => "xori",
+			Opcode::CMPI => "cmpi",
+			Opcode::CMPU => "cmpu",
+			Opcode::DIVU => "divu",
+			Opcode::MODU => "modu",
+			Opcode::SHRU => "shru",
+			Opcode::ERR => "err",
+		}
+	}
+
+	/// Opcode for an assembler mnemonic; `ERR` has none
+	pub fn from_mnemonic(name: &str) -> Option<Opcode> {
+		(0..Opcode::ERR as u8).map(Opcode::from).find(|o| o.mnemonic() == name)
+	}
+}
+
+/// Register bank selected by the top two bits of a register operand
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegLocal {
+	REG32,
+	REG64,
+	REG128,
+}
+
+impl RegLocal {
+	pub fn decode(v: u8) -> Option<RegLocal> {
+		let masked = v >> 6; // Top two bits decide which reg
+		match masked {
+			0 => Some(RegLocal::REG32),
+			1 => Some(RegLocal::REG64),
+			2 => Some(RegLocal::REG128),
+			_ => None,
+		}
+	}
+
+	/// Width of a register in this bank, in bytes
+	pub fn size(self) -> usize {
+		match self {
+			RegLocal::REG32 => 4,
+			RegLocal::REG64 => 8,
+			RegLocal::REG128 => 16,
+		}
+	}
+}
+
+/// How the 32-bit target of a jump is interpreted
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JumpMode {
+	Absolute = 0, // Byte offset from the start of the current script
+	Relative = 1, // Signed byte offset from the jump's own opcode
+}
+
+impl JumpMode {
+	pub fn decode(v: u8) -> Option<JumpMode> {
+		match v {
+			0 => Some(JumpMode::Absolute),
+			1 => Some(JumpMode::Relative),
+			_ => None,
+		}
+	}
+}
@@ -4,57 +4,39 @@
 extern crate nom;
 extern crate bytes;
 
+pub mod arith;
 pub mod asm;
+pub mod cli;
+pub mod container;
+pub mod debugger;
+pub mod decode;
+pub mod disasm;
+pub mod frontend;
+pub mod gas;
 pub mod instruction;
+pub mod repl;
+pub mod snapshot;
+pub mod tracer;
+pub mod verifier;
 pub mod vm;
+pub mod vm_error;
 pub mod vm_script;
 
-use self::bytes::Bytes;
-use instruction::Opcode;
-use vm::VM;
+use std::env;
+use std::io;
+use std::process;
 
 fn main() {
-    let reg = 0;
-    let script = &[
-        Bytes::from(&[Opcode::CAL as u8, 0x0, 0][..]),
-        Bytes::from(&[Opcode::CAL as u8, 0x1, 0][..]),
-        Bytes::from(
-            &[
-                Opcode::LOD as u8,
-                reg,
-                0xFF,
-                0xFF,
-                0xFF,
-                0xFF,
-                Opcode::PSH as u8,
-                reg,
-                0,
-            ][..],
-        ),
-        Bytes::from(
-            &[
-                Opcode::LOD as u8,
-                reg,
-                0x0,
-                0x0,
-                0x0,
-                0xFF,
-                Opcode::PSH as u8,
-                reg,
-                0,
-            ][..],
-        ),
-    ];
-    let mut test_vm = VM::new(script);
-    let ret = test_vm.run();
-    assert_eq!(ret, true);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdout = io::stdout();
+    let code = cli::run(&args, &mut io::stdin(), &mut stdout.lock(), &mut io::stderr());
+    process::exit(code);
 }
 
 #[cfg(test)]
-
 mod tests {
-    use super::*;
     /*
+    use super::*;
     use test::Bencher;
 
     #[bench]
@@ -82,9 +64,9 @@ mod tests {
             ][..],
         );
         let script_arr = [script];
-        let mut test_vm = VM::new(&script_arr);
+        let mut test_vm = VM::new(&script_arr).unwrap();
 
-        b.iter(|| test_vm.run())
+        b.iter(|| test_vm.run(u64::MAX))
     }
 
     #[bench]
@@ -116,9 +98,9 @@ mod tests {
                 ][..],
             ),
         ];
-        let mut test_vm = VM::new(script);
+        let mut test_vm = VM::new(script).unwrap();
 
-        b.iter(|| test_vm.run())
+        b.iter(|| test_vm.run(u64::MAX))
     }
     // */
 }
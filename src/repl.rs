@@ -0,0 +1,255 @@
+extern crate bytes;
+
+use self::bytes::{Bytes, BytesMut};
+use asm::assembler::assemble;
+use disasm::format_reg;
+use frontend::{load, registers, DEFAULT_GAS};
+use instruction::Opcode;
+use std::io::{self, BufRead, Write};
+use vm_script::{ExitStatus, Registers, VMScript};
+
+pub const PROMPT: &str = "> ";
+
+const HELP: &str = "\
+Type an instruction to assemble and execute it, e.g. `ld r0 5` or `addi r0.64 -1`.
+Registers written by the instruction are printed after it runs.
+
+  :regs        show every non-zero register
+  :heap        dump the heap
+  :flags       show the comparison and overflow flags
+  :reset       clear registers, flags and the heap
+  :load FILE   make the scripts in FILE callable, `cal 0` being the first
+  :help        show this text
+  :quit        leave";
+
+/// An interactive machine. Each line is assembled into a one-instruction script and
+/// executed by the same `VMScript`, so registers, flags and the heap carry over.
+pub struct Repl<'a> {
+    vm: VMScript<'a>,
+    // Scripts from `:load`, placed after the line being executed so CAL can reach them
+    libs: Vec<Bytes>,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(heap: &'a mut BytesMut) -> Repl<'a> {
+        // The empty entry script is replaced by each line before it runs
+        let vm = VMScript::new(&[Bytes::new()], heap).unwrap();
+        Repl { vm, libs: vec![] }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.vm.registers()
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        self.vm.heap()
+    }
+
+    /// Handles one line of input and returns the text to print, which may be empty
+    pub fn eval(&mut self, line: &str) -> String {
+        let line = line.trim();
+        if line.is_empty() {
+            return String::new();
+        }
+        if line.starts_with(':') {
+            return self.command(line);
+        }
+        self.execute(line)
+    }
+
+    fn command(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(":regs"), None) => {
+                let regs: Vec<String> = registers(self.vm.registers())
+                    .into_iter()
+                    .map(|(reg, value)| format!("{} = {}", reg, value))
+                    .collect();
+                if regs.is_empty() {
+                    "all registers are zero".to_string()
+                } else {
+                    regs.join("\n")
+                }
+            }
+            (Some(":heap"), None) => {
+                if self.vm.heap().is_empty() {
+                    return "heap is empty".to_string();
+                }
+                let rows: Vec<String> = self
+                    .vm
+                    .heap()
+                    .chunks(16)
+                    .enumerate()
+                    .map(|(i, row)| {
+                        let bytes: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                        format!("{:04x}: {}", i * 16, bytes.join(" "))
+                    })
+                    .collect();
+                rows.join("\n")
+            }
+            (Some(":flags"), None) => {
+                let r = self.vm.registers();
+                format!("eq={} lt={} gt={} of={}", r.f_eq, r.f_lt, r.f_gt, r.f_of)
+            }
+            (Some(":reset"), None) => {
+                self.vm.reset();
+                "machine reset".to_string()
+            }
+            (Some(":load"), Some(file)) => match load(file, &mut io::empty()) {
+                Ok(scripts) => {
+                    let count = scripts.len();
+                    self.libs = scripts;
+                    format!("loaded {} scripts from {}", count, file)
+                }
+                Err(message) => message,
+            },
+            (Some(":help"), None) => HELP.to_string(),
+            _ => format!("unknown command '{}', try :help", line),
+        }
+    }
+
+    fn execute(&mut self, line: &str) -> String {
+        let scripts = match assemble("<repl>", line) {
+            Ok(scripts) => scripts,
+            Err(errors) => {
+                let text: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                return text.join("\n\n");
+            }
+        };
+        if scripts.len() != 1 {
+            return "scripts cannot be defined here, use :load".to_string();
+        }
+        // The line runs as the entry script, with a HLT so it stops after the instruction
+        let mut entry = scripts[0].to_vec();
+        entry.push(Opcode::HLT as u8);
+        self.vm.set_scripts(Bytes::from(entry), &self.libs);
+
+        // Registers the line wrote, in the order it first wrote them
+        let mut written = vec![];
+        self.vm.set_gas(DEFAULT_GAS);
+        let result = loop {
+            match self.vm.step() {
+                Ok(step) => {
+                    for reg in step.written {
+                        if !written.contains(&reg) {
+                            written.push(reg);
+                        }
+                    }
+                    if let Some(status) = step.status {
+                        break Ok(status);
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        let mut out: Vec<String> = written
+            .into_iter()
+            .filter_map(|reg| Some(format!("{} = {}", format_reg(reg)?, self.vm.register(reg)?)))
+            .collect();
+        match result {
+            Ok(ExitStatus::Halted { .. }) => {}
+            Ok(ExitStatus::OutOfGas { gas_used }) => {
+                out.push(format!("out of gas after {}", gas_used))
+            }
+            Err(e) => out.push(format!("error: {}", e)),
+        }
+        out.join("\n")
+    }
+}
+
+/// Reads lines from `input` until end of input or `:quit`, printing a prompt before each
+pub fn run(input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+    let mut heap = BytesMut::new();
+    let mut repl = Repl::new(&mut heap);
+    let mut line = String::new();
+    loop {
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return writeln!(out);
+        }
+        match line.trim() {
+            ":quit" | ":q" => return Ok(()),
+            _ => {}
+        }
+        let reply = repl.eval(&line);
+        if !reply.is_empty() {
+            writeln!(out, "{}", reply)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_repl_state() {
+        let mut heap = BytesMut::new();
+        let mut repl = Repl::new(&mut heap);
+        assert_eq!(repl.eval("ld r0 5"), "r0.32 = 5");
+        assert_eq!(repl.eval("ld r1 5"), "r1.32 = 5");
+        assert_eq!(repl.eval("cmp r0 r1"), "");
+        assert_eq!(repl.eval(":flags"), "eq=true lt=false gt=false of=false");
+        assert_eq!(repl.eval("add r0 r1"), "r0.32 = 10");
+        // Writes are reported even when the value does not change
+        assert_eq!(repl.eval("ld r1 5"), "r1.32 = 5");
+        assert_eq!(repl.eval("psh r0"), "");
+        assert_eq!(repl.eval(":heap"), "0000: 0a 00 00 00");
+        assert_eq!(repl.eval(":regs"), "r0.32 = 10\nr1.32 = 5");
+        assert_eq!(repl.heap(), &[10, 0, 0, 0]);
+
+        assert_eq!(repl.eval(":reset"), "machine reset");
+        assert_eq!(repl.eval(":regs"), "all registers are zero");
+        assert_eq!(repl.eval(":heap"), "heap is empty");
+        assert_eq!(repl.registers(), &Registers::default());
+    }
+
+    #[test]
+    fn test_repl_errors() {
+        let mut heap = BytesMut::new();
+        let mut repl = Repl::new(&mut heap);
+        assert_eq!(repl.eval("   "), "");
+        assert!(repl
+            .eval("ld r0")
+            .starts_with("<repl>:1:6: expected immediate"));
+        assert_eq!(
+            repl.eval("pop r0"),
+            "error: script 0 pc 0x0 (POP): attempted to pop more bytes than exist"
+        );
+        assert_eq!(repl.eval(":load"), "unknown command ':load', try :help");
+        assert!(repl
+            .eval(":load /nonexistent.gasm")
+            .starts_with("error: cannot read"));
+        assert_eq!(
+            repl.eval("cal 0"),
+            "error: script 0 pc 0x0 (CAL): cannot call lib with index 0, out of bounds"
+        );
+    }
+
+    #[test]
+    fn test_repl_load() {
+        let path = env::temp_dir().join(format!("geodesic-repl-{}.gasm", process::id()));
+        fs::write(&path, ".script lib\nld r5 9\nret\n").unwrap();
+        let mut heap = BytesMut::new();
+        let mut repl = Repl::new(&mut heap);
+        let reply = repl.eval(&format!(":load {}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert!(reply.starts_with("loaded 1 scripts"));
+        assert_eq!(repl.eval("cal 0"), "r5.32 = 9");
+    }
+
+    #[test]
+    fn test_repl_run() {
+        let mut input = &b"ld r2.64 -3\n:regs\n:quit\nld r3 1\n"[..];
+        let mut out = vec![];
+        run(&mut input, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> r2.64 = -3\n> r2.64 = -3\n> "
+        );
+    }
+}
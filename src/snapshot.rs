@@ -0,0 +1,664 @@
+use arith::OverflowMode;
+use container::crc32;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use vm::VmConfig;
+use vm_script::{Frame, Registers, REGSIZE};
+
+/// Binary format of a `Snapshot`. All integers are big-endian.
+///
+/// ```text
+/// magic     "GDSN"
+/// version   u16                  1, changed only if this layout changes
+/// fields    u16 count, each a u16 tag, a u32 length and that many bytes
+/// checksum  u32 CRC-32 of every preceding byte
+/// ```
+///
+/// Readers skip tags they do not know and treat missing fields as zero or empty, so
+/// fields can be added without changing the version, and without breaking older
+/// readers or older snapshots. The meaning of an existing tag never changes.
+pub const MAGIC: [u8; 4] = *b"GDSN";
+pub const VERSION: u16 = 1;
+
+// Script index and pc as two u32
+const TAG_POSITION: u16 = 1;
+// eq, lt, gt and of in bits 0 to 3
+const TAG_FLAGS: u16 = 2;
+// One field per bank, a value per register from r0 up
+const TAG_REGS32: u16 = 3;
+const TAG_REGS64: u16 = 4;
+const TAG_REGS128: u16 = 5;
+// rem32, rem64 and rem128
+const TAG_REMAINDERS: u16 = 6;
+const TAG_HEAP: u16 = 7;
+// (u32 script, u32 pc) per frame, the outermost first
+const TAG_CALLS: u16 = 8;
+// u64 gas used so far
+const TAG_GAS: u16 = 9;
+// u64 FNV-1a hash per script the machine was running
+const TAG_SCRIPTS: u16 = 10;
+// u8 overflow mode (0 wrapping, 1 trapping, 2 saturating) and u64 max call depth
+const TAG_CONFIG: u16 = 11;
+// Empty, present only once the machine has halted
+const TAG_HALTED: u16 = 12;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    BadMagic { found: Vec<u8> },
+    UnsupportedVersion { version: u16 },
+    Truncated { offset: usize, needed: usize },
+    // A known field whose length does not fit its contents
+    InvalidField { tag: u16, len: usize },
+    ChecksumMismatch { expected: u32, found: u32 },
+    TrailingBytes { offset: usize },
+    // The snapshot was taken while running other scripts
+    ScriptMismatch,
+    // The pc of the machine or of a call frame lies outside its script
+    InvalidPosition { script: usize, pc: usize },
+    // The snapshot was taken with another overflow mode or call depth limit
+    ConfigMismatch,
+    // `value` does not fit the width the format gives `field`
+    TooLarge { field: &'static str, value: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic { ref found } => {
+                write!(f, "not a snapshot, magic is {:02x?}", found)
+            }
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated { offset, needed } => write!(
+                f,
+                "truncated at offset {:#x}, {} more bytes expected",
+                offset, needed
+            ),
+            SnapshotError::InvalidField { tag, len } => {
+                write!(f, "field {} cannot be {} bytes long", tag, len)
+            }
+            SnapshotError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum {:#010x} does not match contents ({:#010x})",
+                expected, found
+            ),
+            SnapshotError::TrailingBytes { offset } => write!(
+                f,
+                "unexpected data after the checksum at offset {:#x}",
+                offset
+            ),
+            SnapshotError::ScriptMismatch => {
+                write!(f, "snapshot was taken with different scripts")
+            }
+            SnapshotError::InvalidPosition { script, pc } => {
+                write!(f, "pc {:#x} is outside script {}", pc, script)
+            }
+            SnapshotError::ConfigMismatch => {
+                write!(f, "snapshot was taken with a different configuration")
+            }
+            SnapshotError::TooLarge { field, value } => {
+                write!(f, "{} of {} is too large for the snapshot", field, value)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// Everything a `VMScript` needs to carry on from where it was taken, see
+/// `VMScript::snapshot` and `VMScript::restore`. The stack pointer is not stored,
+/// it is always the length of the heap.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Snapshot {
+    pub script: usize,
+    pub pc: usize,
+    pub registers: Registers,
+    pub heap: Vec<u8>,
+    pub calls: Vec<Frame>,
+    pub gas_used: u64,
+    // FNV-1a hashes of the scripts, checked on restore unless empty
+    pub scripts: Vec<u64>,
+    // Configuration of the machine, checked on restore unless None
+    pub config: Option<VmConfig>,
+    // Set once HLT or the last RET has run, resuming then runs nothing
+    pub halted: bool,
+}
+
+impl Snapshot {
+    /// Fails if a position, a length or the number of fields does not fit its field
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        let r = &self.registers;
+        let mut fields: Vec<(u16, Vec<u8>)> = vec![];
+
+        let mut position = vec![];
+        position.extend_from_slice(&u32_field("script", self.script)?.to_be_bytes());
+        position.extend_from_slice(&u32_field("pc", self.pc)?.to_be_bytes());
+        fields.push((TAG_POSITION, position));
+
+        let flags = r.f_eq as u8 | (r.f_lt as u8) << 1 | (r.f_gt as u8) << 2 | (r.f_of as u8) << 3;
+        fields.push((TAG_FLAGS, vec![flags]));
+
+        fields.push((
+            TAG_REGS32,
+            r.regs32
+                .iter()
+                .flat_map(|v| v.to_be_bytes().to_vec())
+                .collect(),
+        ));
+        fields.push((
+            TAG_REGS64,
+            r.regs64
+                .iter()
+                .flat_map(|v| v.to_be_bytes().to_vec())
+                .collect(),
+        ));
+        fields.push((
+            TAG_REGS128,
+            r.regs128
+                .iter()
+                .flat_map(|v| v.to_be_bytes().to_vec())
+                .collect(),
+        ));
+
+        let mut rems = vec![];
+        rems.extend_from_slice(&r.rem32.to_be_bytes());
+        rems.extend_from_slice(&r.rem64.to_be_bytes());
+        rems.extend_from_slice(&r.rem128.to_be_bytes());
+        fields.push((TAG_REMAINDERS, rems));
+
+        fields.push((TAG_HEAP, self.heap.clone()));
+
+        let mut calls = vec![];
+        for frame in &self.calls {
+            calls.extend_from_slice(&u32_field("call frame script", frame.script)?.to_be_bytes());
+            calls.extend_from_slice(&u32_field("call frame pc", frame.pc)?.to_be_bytes());
+        }
+        fields.push((TAG_CALLS, calls));
+
+        fields.push((TAG_GAS, self.gas_used.to_be_bytes().to_vec()));
+        fields.push((
+            TAG_SCRIPTS,
+            self.scripts
+                .iter()
+                .flat_map(|h| h.to_be_bytes().to_vec())
+                .collect(),
+        ));
+        if let Some(config) = self.config {
+            let mut body = vec![overflow_code(config.overflow)];
+            body.extend_from_slice(&(config.max_call_depth as u64).to_be_bytes());
+            fields.push((TAG_CONFIG, body));
+        }
+        if self.halted {
+            fields.push((TAG_HALTED, vec![]));
+        }
+
+        let mut out = vec![];
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&VERSION.to_be_bytes());
+        out.extend_from_slice(&u16_field("field count", fields.len())?.to_be_bytes());
+        for (tag, body) in fields {
+            out.extend_from_slice(&tag.to_be_bytes());
+            out.extend_from_slice(&u32_field("field", body.len())?.to_be_bytes());
+            out.extend_from_slice(&body);
+        }
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let bytes = self
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        w.write_all(&bytes)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut r = Reader { data, offset: 0 };
+        let magic = r.take(MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(SnapshotError::BadMagic {
+                found: magic.to_vec(),
+            });
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut snapshot = Snapshot::default();
+        for _ in 0..r.u16()? {
+            let tag = r.u16()?;
+            let len = r.u32()? as usize;
+            let body = r.take(len)?;
+            snapshot.read_field(tag, body)?;
+        }
+
+        let found = crc32(&data[..r.offset]);
+        let expected = r.u32()?;
+        if expected != found {
+            return Err(SnapshotError::ChecksumMismatch { expected, found });
+        }
+        if r.offset != data.len() {
+            return Err(SnapshotError::TrailingBytes { offset: r.offset });
+        }
+        Ok(snapshot)
+    }
+
+    fn read_field(&mut self, tag: u16, body: &[u8]) -> Result<(), SnapshotError> {
+        let invalid = SnapshotError::InvalidField {
+            tag,
+            len: body.len(),
+        };
+        let r = &mut self.registers;
+        match tag {
+            TAG_POSITION if body.len() == 8 => {
+                self.script = be(&body[..4]) as usize;
+                self.pc = be(&body[4..]) as usize;
+            }
+            TAG_FLAGS if body.len() == 1 => {
+                r.f_eq = body[0] & 1 != 0;
+                r.f_lt = body[0] & 2 != 0;
+                r.f_gt = body[0] & 4 != 0;
+                r.f_of = body[0] & 8 != 0;
+            }
+            // Banks may hold fewer registers than this build, the rest stay zero
+            TAG_REGS32 if body.len().is_multiple_of(4) && body.len() <= REGSIZE * 4 => {
+                for (reg, v) in r.regs32.iter_mut().zip(body.chunks(4)) {
+                    *reg = be(v) as i32;
+                }
+            }
+            TAG_REGS64 if body.len().is_multiple_of(8) && body.len() <= REGSIZE * 8 => {
+                for (reg, v) in r.regs64.iter_mut().zip(body.chunks(8)) {
+                    *reg = be(v) as i64;
+                }
+            }
+            TAG_REGS128 if body.len().is_multiple_of(16) && body.len() <= REGSIZE * 16 => {
+                for (reg, v) in r.regs128.iter_mut().zip(body.chunks(16)) {
+                    *reg = be(v) as i128;
+                }
+            }
+            TAG_REMAINDERS if body.len() == 28 => {
+                r.rem32 = be(&body[..4]) as i32;
+                r.rem64 = be(&body[4..12]) as i64;
+                r.rem128 = be(&body[12..]) as i128;
+            }
+            TAG_HEAP => self.heap = body.to_vec(),
+            TAG_CALLS if body.len().is_multiple_of(8) => {
+                self.calls = body
+                    .chunks(8)
+                    .map(|f| Frame {
+                        script: be(&f[..4]) as usize,
+                        pc: be(&f[4..]) as usize,
+                    })
+                    .collect();
+            }
+            TAG_GAS if body.len() == 8 => self.gas_used = be(body) as u64,
+            TAG_SCRIPTS if body.len().is_multiple_of(8) => {
+                self.scripts = body.chunks(8).map(|h| be(h) as u64).collect();
+            }
+            TAG_CONFIG if body.len() == 9 => {
+                let overflow = match body[0] {
+                    0 => OverflowMode::Wrapping,
+                    1 => OverflowMode::Trapping,
+                    2 => OverflowMode::Saturating,
+                    _ => return Err(invalid),
+                };
+                self.config = Some(VmConfig {
+                    overflow,
+                    max_call_depth: be(&body[1..]) as usize,
+                });
+            }
+            TAG_HALTED if body.is_empty() => self.halted = true,
+            TAG_POSITION..=TAG_HALTED => return Err(invalid),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let available = self.data.len() - self.offset;
+        if len > available {
+            return Err(SnapshotError::Truncated {
+                offset: self.offset,
+                needed: len - available,
+            });
+        }
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(be(self.take(2)?) as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(be(self.take(4)?) as u32)
+    }
+}
+
+fn u16_field(field: &'static str, value: usize) -> Result<u16, SnapshotError> {
+    if value > u16::MAX as usize {
+        return Err(SnapshotError::TooLarge { field, value });
+    }
+    Ok(value as u16)
+}
+
+fn u32_field(field: &'static str, value: usize) -> Result<u32, SnapshotError> {
+    if value > u32::MAX as usize {
+        return Err(SnapshotError::TooLarge { field, value });
+    }
+    Ok(value as u32)
+}
+
+fn overflow_code(mode: OverflowMode) -> u8 {
+    match mode {
+        OverflowMode::Wrapping => 0,
+        OverflowMode::Trapping => 1,
+        OverflowMode::Saturating => 2,
+    }
+}
+
+// Big-endian value of up to 16 bytes
+fn be(b: &[u8]) -> u128 {
+    b.iter().fold(0, |acc, v| (acc << 8) | u128::from(*v))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate bytes;
+
+    use self::bytes::{Bytes, BytesMut};
+    use super::*;
+    use asm::assembler::assemble;
+    use vm::{VmConfig, VM};
+    use vm_script::VMScript;
+
+    const SOURCE: &str = "\
+ld r0 3
+ld r1.128 -5
+ld r2.128 2
+div r1.128 r2.128
+loop: cal body
+cmpi r0 0
+jgt loop
+hlt
+.script body
+psh r0
+subi r0 1
+ret
+";
+
+    fn sample() -> Snapshot {
+        let mut s = Snapshot {
+            script: 1,
+            pc: 4,
+            heap: vec![1, 2, 3],
+            calls: vec![Frame { script: 0, pc: 9 }],
+            gas_used: 77,
+            scripts: vec![0xDEAD_BEEF, 1],
+            config: Some(VmConfig {
+                max_call_depth: 3,
+                overflow: OverflowMode::Saturating,
+            }),
+            ..Snapshot::default()
+        };
+        s.registers.regs32[0] = -1;
+        s.registers.regs64[62] = i64::MIN;
+        s.registers.regs128[5] = i128::MAX;
+        s.registers.rem64 = -3;
+        s.registers.f_gt = true;
+        s.registers.f_of = true;
+        s
+    }
+
+    // Rewrites the trailing checksum after tampering with the contents
+    fn reseal(data: &mut Vec<u8>) {
+        let len = data.len() - 4;
+        let checksum = crc32(&data[..len]);
+        data.truncate(len);
+        data.extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let s = sample();
+        let data = s.to_bytes().unwrap();
+        assert_eq!(&data[..4], b"GDSN");
+        assert_eq!(Snapshot::from_bytes(&data), Ok(s.clone()));
+
+        let mut written = vec![];
+        s.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+
+        let empty = Snapshot::default();
+        assert_eq!(Snapshot::from_bytes(&empty.to_bytes().unwrap()), Ok(empty));
+
+        let halted = Snapshot {
+            halted: true,
+            ..sample()
+        };
+        assert_eq!(
+            Snapshot::from_bytes(&halted.to_bytes().unwrap()),
+            Ok(halted)
+        );
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let far = u32::MAX as usize + 1;
+        let s = Snapshot {
+            pc: far,
+            ..sample()
+        };
+        assert_eq!(
+            s.to_bytes(),
+            Err(SnapshotError::TooLarge {
+                field: "pc",
+                value: far
+            })
+        );
+        let mut written = vec![];
+        let e = s.write_to(&mut written).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(written.is_empty());
+
+        let mut s = sample();
+        s.calls.push(Frame { script: far, pc: 0 });
+        assert_eq!(
+            s.to_bytes(),
+            Err(SnapshotError::TooLarge {
+                field: "call frame script",
+                value: far
+            })
+        );
+    }
+
+    #[test]
+    fn test_compatibility() {
+        // The layout is fixed, this is a version 1 snapshot byte for byte
+        let s = Snapshot {
+            pc: 2,
+            ..Snapshot::default()
+        };
+        let data = s.to_bytes().unwrap();
+        assert_eq!(&data[..14], b"GDSN\x00\x01\x00\x0a\x00\x01\x00\x00\x00\x08");
+        assert_eq!(&data[14..22], &[0, 0, 0, 0, 0, 0, 0, 2]);
+
+        // Unknown fields from a later version are skipped, missing ones left at zero
+        let mut data = vec![];
+        data.extend_from_slice(b"GDSN\x00\x01\x00\x02");
+        data.extend_from_slice(&[0x01, 0x00, 0, 0, 0, 3, 0xAA, 0xBB, 0xCC]);
+        data.extend_from_slice(&[0x00, 0x07, 0, 0, 0, 2, 0x10, 0x20]);
+        data.extend_from_slice(&[0; 4]);
+        reseal(&mut data);
+        let read = Snapshot::from_bytes(&data).unwrap();
+        assert_eq!(
+            read,
+            Snapshot {
+                heap: vec![0x10, 0x20],
+                ..Snapshot::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let data = sample().to_bytes().unwrap();
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::BadMagic { .. })
+        ));
+
+        let mut bad = data.clone();
+        bad[5] = 2;
+        reseal(&mut bad);
+        assert_eq!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::UnsupportedVersion { version: 2 })
+        );
+
+        let mut bad = data.clone();
+        bad[20] ^= 1;
+        assert!(matches!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::ChecksumMismatch { .. })
+        ));
+
+        // The position field claims 7 bytes instead of 8
+        let mut bad = data.clone();
+        bad[13] = 7;
+        reseal(&mut bad);
+        assert_eq!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::InvalidField { tag: 1, len: 7 })
+        );
+
+        // The overflow mode is the first byte of the last field
+        let mut bad = data.clone();
+        let mode = data.len() - 4 - 9;
+        bad[mode] = 3;
+        reseal(&mut bad);
+        assert_eq!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::InvalidField { tag: 11, len: 9 })
+        );
+
+        let mut bad = data.clone();
+        bad.push(0);
+        assert_eq!(
+            Snapshot::from_bytes(&bad),
+            Err(SnapshotError::TrailingBytes { offset: data.len() })
+        );
+
+        for len in 0..data.len() {
+            assert!(matches!(
+                Snapshot::from_bytes(&data[..len]),
+                Err(SnapshotError::Truncated { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let scripts = assemble("test", SOURCE).unwrap();
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&scripts, &mut heap).unwrap();
+        let finished = vm.run(u64::MAX).unwrap();
+        let expected = vm.snapshot();
+
+        // Stop inside the callee, persist, and carry on in a fresh machine
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&scripts, &mut heap).unwrap();
+        while vm.calls().is_empty() || vm.pc() == 0 {
+            vm.step().unwrap();
+        }
+        let data = vm.snapshot().to_bytes().unwrap();
+
+        let snapshot = Snapshot::from_bytes(&data).unwrap();
+        assert_eq!(snapshot.calls.len(), 1);
+        let mut heap = BytesMut::new();
+        let mut resumed = VMScript::new(&scripts, &mut heap).unwrap();
+        resumed.restore(&snapshot).unwrap();
+        assert_eq!(resumed.resume(u64::MAX), Ok(finished));
+        assert_eq!(resumed.snapshot(), expected);
+
+        // Forking: the same snapshot resumes any number of times
+        let mut vm = VM::from_snapshot(&scripts, VmConfig::default(), &snapshot).unwrap();
+        assert_eq!(vm.resume(u64::MAX), Ok(finished));
+        assert_eq!(&vm.heap[..], &expected.heap[..]);
+        assert_eq!(
+            vm.snapshot().map(|s| &s.registers),
+            Some(&expected.registers)
+        );
+    }
+
+    #[test]
+    fn test_restore_checks() {
+        let scripts = assemble("test", SOURCE).unwrap();
+        let mut heap = BytesMut::new();
+        let snapshot = VMScript::new(&scripts, &mut heap).unwrap().snapshot();
+
+        let other = [Bytes::from(&[0][..])];
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&other, &mut heap).unwrap();
+        assert_eq!(vm.restore(&snapshot), Err(SnapshotError::ScriptMismatch));
+
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&scripts, &mut heap).unwrap();
+        let bad = Snapshot {
+            script: 2,
+            ..snapshot.clone()
+        };
+        assert_eq!(
+            vm.restore(&bad),
+            Err(SnapshotError::InvalidPosition { script: 2, pc: 0 })
+        );
+        let bad = Snapshot {
+            calls: vec![Frame { script: 1, pc: 99 }],
+            ..snapshot.clone()
+        };
+        assert_eq!(
+            vm.restore(&bad),
+            Err(SnapshotError::InvalidPosition { script: 1, pc: 99 })
+        );
+
+        let trapping = VmConfig {
+            overflow: OverflowMode::Trapping,
+            ..VmConfig::default()
+        };
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::with_config(&scripts, &mut heap, trapping).unwrap();
+        assert_eq!(vm.restore(&snapshot), Err(SnapshotError::ConfigMismatch));
+        assert_eq!(
+            VM::from_snapshot(&scripts, trapping, &snapshot).err(),
+            Some(SnapshotError::ConfigMismatch)
+        );
+
+        // Without hashes or a configuration any scripts and configuration are accepted
+        let unchecked = Snapshot {
+            scripts: vec![],
+            config: None,
+            ..snapshot
+        };
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::new(&other, &mut heap).unwrap();
+        assert_eq!(vm.restore(&unchecked), Ok(()));
+        let mut heap = BytesMut::new();
+        let mut vm = VMScript::with_config(&scripts, &mut heap, trapping).unwrap();
+        assert_eq!(vm.restore(&unchecked), Ok(()));
+    }
+}
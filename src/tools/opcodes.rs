@@ -18,6 +18,30 @@ pub enum Opcode{
 	CMP,
 	PSH,
 	POP,
+	JMP,
+	JEQ,
+	JNE,
+	JLT,
+	JGT,
+	JLE,
+	JGE,
+	RET,
+	REM,
+	MOV,
+	SEXT,
+	ZEXT,
+	TRUNC,
+	ADDI,
+	SUBI,
+	MULI,
+	ANDI,
+	ORI,
+	XORI,
+	CMPI,
+	CMPU,
+	DIVU,
+	MODU,
+	SHRU,
 	ERR,
 }
 
@@ -43,6 +67,30 @@ impl From<u8> for Opcode {
 			0x10 => Opcode::CMP,
 			0x11 => Opcode::PSH,
 			0x12 => Opcode::POP,
+			0x13 => Opcode::JMP,
+			0x14 => Opcode::JEQ,
+			0x15 => Opcode::JNE,
+			0x16 => Opcode::JLT,
+			0x17 => Opcode::JGT,
+			0x18 => Opcode::JLE,
+			0x19 => Opcode::JGE,
+			0x1A => Opcode::RET,
+			0x1B => Opcode::REM,
+			0x1C => Opcode::MOV,
+			0x1D => Opcode::SEXT,
+			0x1E => Opcode::ZEXT,
+			0x1F => Opcode::TRUNC,
+			0x20 => Opcode::ADDI,
+			0x21 => Opcode::SUBI,
+			0x22 => Opcode::MULI,
+			0x23 => Opcode::ANDI,
+			0x24 => Opcode::ORI,
+			0x25 => Opcode::XORI,
+			0x26 => Opcode::CMPI,
+			0x27 => Opcode::CMPU,
+			0x28 => Opcode::DIVU,
+			0x29 => Opcode::MODU,
+			0x2A => Opcode::SHRU,
 			_=> Opcode::ERR
 		}
 	}
@@ -67,3 +115,27 @@ Opcode::CAL => {}
 Opcode::CMP => {}
 Opcode::PSH => {}
 Opcode::POP => {}
+Opcode::JMP => {}
+Opcode::JEQ => {}
+Opcode::JNE => {}
+Opcode::JLT => {}
+Opcode::JGT => {}
+Opcode::JLE => {}
+Opcode::JGE => {}
+Opcode::RET => {}
+Opcode::REM => {}
+Opcode::MOV => {}
+Opcode::SEXT => {}
+Opcode::ZEXT => {}
+Opcode::TRUNC => {}
+Opcode::ADDI => {}
+Opcode::SUBI => {}
+Opcode::MULI => {}
+Opcode::ANDI => {}
+Opcode::ORI => {}
+Opcode::XORI => {}
+Opcode::CMPI => {}
+Opcode::CMPU => {}
+Opcode::DIVU => {}
+Opcode::MODU => {}
+Opcode::SHRU => {}
@@ -0,0 +1,244 @@
+use instruction::Opcode;
+use std::io::{self, Write};
+use vm_error::VmError;
+use vm_script::ExitStatus;
+
+/// Hooks called by `VM::run_with` and `VMScript::run_with` as a script executes.
+/// Every method does nothing by default, and since runs are generic over the tracer
+/// the calls into `NoTracer` compile away.
+pub trait Tracer {
+    /// The instruction at `pc` in script `script` has been paid for and is about to run
+    fn before_instruction(&mut self, _script: usize, _pc: usize, _op: Opcode) {}
+
+    /// The instruction completed without an error, `gas_used` includes its cost
+    fn after_instruction(&mut self, _script: usize, _pc: usize, _op: Opcode, _gas_used: u64) {}
+
+    /// The CAL at `pc` in script `script` passes control to script `target`
+    fn on_call(&mut self, _script: usize, _pc: usize, _target: usize) {}
+
+    /// `bytes` were pushed onto the heap, the first of them at `offset`
+    fn on_heap_write(&mut self, _offset: usize, _bytes: &[u8]) {}
+
+    /// The run is over, whether it halted, ran out of gas or failed
+    fn on_halt(&mut self, _result: &Result<ExitStatus, VmError>) {}
+}
+
+/// The tracer used by `run`, which ignores everything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoTracer;
+
+impl Tracer for NoTracer {}
+
+/// Writes one JSON object per event and line, for tools to consume:
+///
+/// ```text
+/// {"event":"before","script":0,"pc":6,"op":"psh"}
+/// {"event":"heap_write","offset":0,"bytes":"07000000"}
+/// {"event":"after","script":0,"pc":6,"op":"psh","gas_used":5}
+/// {"event":"call","script":0,"pc":8,"target":1}
+/// {"event":"halt","status":"halted","gas_used":28}
+/// ```
+///
+/// `status` is one of `halted`, `out_of_gas` or `error`, the last with an `error` message.
+/// Writing stops at the first I/O error, which `finish` returns.
+pub struct JsonTracer<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> JsonTracer<W> {
+        JsonTracer { out, error: None }
+    }
+
+    /// Flushes and returns the writer, or the first error met while tracing
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn line(&mut self, json: &str) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", json) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn before_instruction(&mut self, script: usize, pc: usize, op: Opcode) {
+        self.line(&format!(
+            r#"{{"event":"before","script":{},"pc":{},"op":"{}"}}"#,
+            script,
+            pc,
+            op.mnemonic()
+        ));
+    }
+
+    fn after_instruction(&mut self, script: usize, pc: usize, op: Opcode, gas_used: u64) {
+        self.line(&format!(
+            r#"{{"event":"after","script":{},"pc":{},"op":"{}","gas_used":{}}}"#,
+            script,
+            pc,
+            op.mnemonic(),
+            gas_used
+        ));
+    }
+
+    fn on_call(&mut self, script: usize, pc: usize, target: usize) {
+        self.line(&format!(
+            r#"{{"event":"call","script":{},"pc":{},"target":{}}}"#,
+            script, pc, target
+        ));
+    }
+
+    fn on_heap_write(&mut self, offset: usize, bytes: &[u8]) {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.line(&format!(
+            r#"{{"event":"heap_write","offset":{},"bytes":"{}"}}"#,
+            offset, hex
+        ));
+    }
+
+    fn on_halt(&mut self, result: &Result<ExitStatus, VmError>) {
+        let line = match *result {
+            Ok(ExitStatus::Halted { gas_used }) => format!(
+                r#"{{"event":"halt","status":"halted","gas_used":{}}}"#,
+                gas_used
+            ),
+            Ok(ExitStatus::OutOfGas { gas_used }) => format!(
+                r#"{{"event":"halt","status":"out_of_gas","gas_used":{}}}"#,
+                gas_used
+            ),
+            Err(ref e) => format!(
+                r#"{{"event":"halt","status":"error","error":"{}"}}"#,
+                escape(&e.to_string())
+            ),
+        };
+        self.line(&line);
+    }
+}
+
+// Escapes `s` for use inside a JSON string
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate bytes;
+
+    use self::bytes::Bytes;
+    use super::*;
+    use vm::VM;
+
+    // Counts events, to check the hooks fire where they should
+    #[derive(Default)]
+    struct Counter {
+        before: usize,
+        after: usize,
+        calls: Vec<(usize, usize, usize)>,
+        pushed: Vec<u8>,
+        halts: usize,
+    }
+
+    impl Tracer for Counter {
+        fn before_instruction(&mut self, _: usize, _: usize, _: Opcode) {
+            self.before += 1;
+        }
+
+        fn after_instruction(&mut self, _: usize, _: usize, _: Opcode, _: u64) {
+            self.after += 1;
+        }
+
+        fn on_call(&mut self, script: usize, pc: usize, target: usize) {
+            self.calls.push((script, pc, target));
+        }
+
+        fn on_heap_write(&mut self, _: usize, bytes: &[u8]) {
+            self.pushed.extend_from_slice(bytes);
+        }
+
+        fn on_halt(&mut self, _: &Result<ExitStatus, VmError>) {
+            self.halts += 1;
+        }
+    }
+
+    fn scripts() -> [Bytes; 2] {
+        [
+            Bytes::from(&[Opcode::NOP as u8, Opcode::CAL as u8, 0, Opcode::HLT as u8][..]),
+            Bytes::from(
+                &[
+                    Opcode::INC as u8,
+                    0,
+                    Opcode::PSH as u8,
+                    0,
+                    Opcode::RET as u8,
+                ][..],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_hooks() {
+        let scripts = scripts();
+        let mut counter = Counter::default();
+        let mut vm = VM::new(&scripts).unwrap();
+        assert!(vm.run_with(u64::MAX, &mut counter).is_ok());
+        assert_eq!((counter.before, counter.after, counter.halts), (6, 6, 1));
+        assert_eq!(counter.calls, vec![(0, 1, 1)]);
+        assert_eq!(counter.pushed, vec![1, 0, 0, 0]);
+
+        // A failing instruction is announced but never completes
+        let scripts = [Bytes::from(&[Opcode::POP as u8, 0][..])];
+        let mut counter = Counter::default();
+        assert!(VM::new(&scripts).unwrap().run_with(u64::MAX, &mut counter).is_err());
+        assert_eq!((counter.before, counter.after, counter.halts), (1, 0, 1));
+    }
+
+    #[test]
+    fn test_json_tracer() {
+        let scripts = scripts();
+        let mut tracer = JsonTracer::new(vec![]);
+        VM::new(&scripts).unwrap().run_with(u64::MAX, &mut tracer).unwrap();
+        let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 6 * 2 + 3);
+        assert_eq!(
+            lines[0],
+            r#"{"event":"before","script":0,"pc":0,"op":"nop"}"#
+        );
+        assert_eq!(lines[3], r#"{"event":"call","script":0,"pc":1,"target":1}"#);
+        assert!(lines.contains(&r#"{"event":"heap_write","offset":0,"bytes":"01000000"}"#));
+        assert!(lines[14].starts_with(r#"{"event":"halt","status":"halted","gas_used":"#));
+
+        let scripts = [Bytes::from(&[Opcode::POP as u8, 0][..])];
+        let mut tracer = JsonTracer::new(vec![]);
+        VM::new(&scripts).unwrap()
+            .run_with(u64::MAX, &mut tracer)
+            .unwrap_err();
+        let out = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        assert!(out.ends_with(
+            "{\"event\":\"halt\",\"status\":\"error\",\"error\":\"script 0 pc 0x0 (POP): attempted to pop more bytes than exist\"}\n"
+        ));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a \"b\"\\\n\t"), "a \\\"b\\\"\\\\\\n\\u0009");
+    }
+}
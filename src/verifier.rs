@@ -0,0 +1,352 @@
+extern crate bytes;
+
+use self::bytes::Bytes;
+use decode::{decode, Arg, DecodeError, Instruction};
+use instruction::{Opcode, Operand, RegLocal};
+use std::collections::HashMap;
+use std::fmt;
+use vm_script::REGSIZE;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Problem {
+    UnknownOpcode { opcode: u8 },
+    TruncatedOperand,
+    // Top two bits are 0b11, which selects no bank
+    InvalidRegister { reg: u8 },
+    // Index past the end of its bank
+    RegisterOutOfRange { reg: u8 },
+    WidthMismatch { reg1: u8, reg2: u8 },
+    InvalidJumpMode { mode: u8 },
+    // Outside the script or not at the start of an instruction
+    InvalidJump { target: i64 },
+    CallOutOfBounds { index: usize },
+    // No HLT or RET can be reached from the start of the script
+    NoReachableHalt,
+    // The library is empty, reported as script 0 offset 0
+    NoScripts,
+}
+
+/// A problem found in the instruction at `offset` of script `script`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub script: usize,
+    pub offset: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "script {} offset {:#x}: ", self.script, self.offset)?;
+        match self.problem {
+            Problem::UnknownOpcode { opcode } => write!(f, "unknown opcode {:#x}", opcode),
+            Problem::TruncatedOperand => write!(f, "script ends inside operands"),
+            Problem::InvalidRegister { reg } => write!(f, "invalid register {:#x}", reg),
+            Problem::RegisterOutOfRange { reg } => {
+                write!(f, "register {:#x} is past the last of its bank", reg)
+            }
+            Problem::WidthMismatch { reg1, reg2 } => {
+                write!(
+                    f,
+                    "registers {:#x} and {:#x} have incompatible widths",
+                    reg1, reg2
+                )
+            }
+            Problem::InvalidJumpMode { mode } => write!(f, "invalid jump mode {:#x}", mode),
+            Problem::InvalidJump { target } => {
+                write!(
+                    f,
+                    "jump target {} is not an instruction in this script",
+                    target
+                )
+            }
+            Problem::CallOutOfBounds { index } => write!(f, "cannot call lib with index {}", index),
+            Problem::NoReachableHalt => write!(f, "no reachable HLT"),
+            Problem::NoScripts => write!(f, "no entry script, the library is empty"),
+        }
+    }
+}
+
+/// Checks every script before it is run, so malformed bytecode is rejected
+/// without spending any gas. An empty result means nothing was found.
+pub fn verify(scripts: &[Bytes]) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    if scripts.is_empty() {
+        diags.push(Diagnostic {
+            script: 0,
+            offset: 0,
+            problem: Problem::NoScripts,
+        });
+    }
+    for (idx, script) in scripts.iter().enumerate() {
+        verify_script(scripts.len(), idx, script, &mut diags);
+    }
+    diags
+}
+
+fn verify_script(num_scripts: usize, idx: usize, script: &Bytes, diags: &mut Vec<Diagnostic>) {
+    let mut report = |offset: usize, problem: Problem| {
+        diags.push(Diagnostic {
+            script: idx,
+            offset,
+            problem,
+        })
+    };
+
+    // Decode linearly, the length of a bad instruction is unknown so stop at the first
+    let mut insts = vec![];
+    let mut complete = true;
+    let mut pc = 0;
+    while pc < script.len() {
+        match decode(script, pc) {
+            Ok(inst) => {
+                pc += inst.len;
+                insts.push(inst);
+            }
+            Err(e) => {
+                let (offset, problem) = match e {
+                    DecodeError::UnknownOpcode { offset, opcode } => {
+                        (offset, Problem::UnknownOpcode { opcode })
+                    }
+                    DecodeError::Truncated { offset } => (offset, Problem::TruncatedOperand),
+                    DecodeError::InvalidRegister { offset, reg } => {
+                        (offset, Problem::InvalidRegister { reg })
+                    }
+                    DecodeError::InvalidJumpMode { offset, mode } => {
+                        (offset, Problem::InvalidJumpMode { mode })
+                    }
+                };
+                report(offset, problem);
+                complete = false;
+                break;
+            }
+        }
+    }
+    let starts: HashMap<usize, usize> = insts
+        .iter()
+        .enumerate()
+        .map(|(i, inst)| (inst.offset, i))
+        .collect();
+
+    for inst in &insts {
+        for arg in &inst.args {
+            if let Arg::Reg(reg) = *arg {
+                if RegLocal::decode(reg).is_none() {
+                    report(inst.offset, Problem::InvalidRegister { reg });
+                } else if (reg & 0x3F) as usize >= REGSIZE {
+                    report(inst.offset, Problem::RegisterOutOfRange { reg });
+                }
+            }
+        }
+        if let Some(problem) = check_widths(inst) {
+            report(inst.offset, problem);
+        }
+        if let Some(&Arg::Byte(index)) = inst.args.first() {
+            if inst.opcode == Opcode::CAL && idx + 1 + index as usize >= num_scripts {
+                report(
+                    inst.offset,
+                    Problem::CallOutOfBounds {
+                        index: index as usize,
+                    },
+                );
+            }
+        }
+        if let Some(target) = inst.jump_target() {
+            if target < 0 || !starts.contains_key(&(target as usize)) {
+                report(inst.offset, Problem::InvalidJump { target });
+            }
+        }
+    }
+
+    // Only meaningful once the whole script decoded
+    if complete && !reaches_halt(&insts, &starts) {
+        report(0, Problem::NoReachableHalt);
+    }
+}
+
+// Binary ops need both registers from one bank, SEXT/ZEXT a wider destination
+// and TRUNC a narrower one
+fn check_widths(inst: &Instruction) -> Option<Problem> {
+    if inst.opcode.operands() != [Operand::Reg, Operand::Reg] {
+        return None;
+    }
+    let (reg1, reg2) = match (inst.args[0], inst.args[1]) {
+        (Arg::Reg(reg1), Arg::Reg(reg2)) => (reg1, reg2),
+        _ => return None,
+    };
+    let (r1, r2) = match (RegLocal::decode(reg1), RegLocal::decode(reg2)) {
+        (Some(r1), Some(r2)) => (r1, r2),
+        // Already reported as invalid registers
+        _ => return None,
+    };
+    let valid = match inst.opcode {
+        Opcode::SEXT | Opcode::ZEXT => r1.size() > r2.size(),
+        Opcode::TRUNC => r1.size() < r2.size(),
+        _ => r1 == r2,
+    };
+    if valid {
+        None
+    } else {
+        Some(Problem::WidthMismatch { reg1, reg2 })
+    }
+}
+
+// Walks every path from the first instruction looking for a HLT or RET
+fn reaches_halt(insts: &[Instruction], starts: &HashMap<usize, usize>) -> bool {
+    let mut seen = vec![false; insts.len()];
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        if i >= insts.len() || seen[i] {
+            continue;
+        }
+        seen[i] = true;
+        let inst = &insts[i];
+        match inst.opcode {
+            Opcode::HLT | Opcode::RET => return true,
+            Opcode::JMP => {}
+            _ => work.push(i + 1),
+        }
+        if let Some(&next) = inst.jump_target().and_then(|t| starts.get(&(t as usize))) {
+            work.push(next);
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruction::JumpMode;
+
+    fn problems(scripts: &[Bytes]) -> Vec<(usize, usize, Problem)> {
+        verify(scripts)
+            .into_iter()
+            .map(|d| (d.script, d.offset, d.problem))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_valid() {
+        let scripts = [
+            Bytes::from(&[Opcode::CAL as u8, 0, Opcode::INC as u8, 0, 0][..]),
+            Bytes::from(
+                &[
+                    Opcode::JMP as u8,
+                    JumpMode::Absolute as u8,
+                    0,
+                    0,
+                    0,
+                    6,
+                    Opcode::RET as u8,
+                ][..],
+            ),
+        ];
+        assert_eq!(problems(&scripts), vec![]);
+    }
+
+    #[test]
+    fn test_verify_decode_errors() {
+        let scripts = [
+            Bytes::from(&[Opcode::NOP as u8, 0xFE, 0][..]),
+            Bytes::from(&[Opcode::LOD as u8, 0, 0xFF][..]),
+        ];
+        assert_eq!(
+            problems(&scripts),
+            vec![
+                (0, 1, Problem::UnknownOpcode { opcode: 0xFE }),
+                (1, 0, Problem::TruncatedOperand),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_registers() {
+        let scripts = [Bytes::from(
+            &[
+                Opcode::INC as u8,
+                0xC1,
+                Opcode::NOT as u8,
+                0x3F,
+                Opcode::ADD as u8,
+                0,
+                1 << 6,
+                0,
+            ][..],
+        )];
+        assert_eq!(
+            problems(&scripts),
+            vec![
+                (0, 0, Problem::InvalidRegister { reg: 0xC1 }),
+                (0, 2, Problem::RegisterOutOfRange { reg: 0x3F }),
+                (
+                    0,
+                    4,
+                    Problem::WidthMismatch {
+                        reg1: 0,
+                        reg2: 1 << 6
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_calls_and_jumps() {
+        let scripts = [
+            Bytes::from(
+                &[
+                    Opcode::CAL as u8,
+                    1,
+                    Opcode::JEQ as u8,
+                    JumpMode::Relative as u8,
+                    0,
+                    0,
+                    0,
+                    1,
+                    0,
+                ][..],
+            ),
+            Bytes::from(&[Opcode::RET as u8][..]),
+        ];
+        assert_eq!(
+            problems(&scripts),
+            vec![
+                (0, 0, Problem::CallOutOfBounds { index: 1 }),
+                (0, 2, Problem::InvalidJump { target: 3 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_no_reachable_halt() {
+        // The HLT sits behind an unconditional backwards jump
+        let scripts = [
+            Bytes::from(
+                &[
+                    Opcode::NOP as u8,
+                    Opcode::JMP as u8,
+                    JumpMode::Absolute as u8,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ][..],
+            ),
+            Bytes::from(&[Opcode::NOP as u8][..]),
+            Bytes::new(),
+        ];
+        assert_eq!(
+            problems(&scripts),
+            vec![
+                (0, 0, Problem::NoReachableHalt),
+                (1, 0, Problem::NoReachableHalt),
+                (2, 0, Problem::NoReachableHalt),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_empty_library() {
+        assert_eq!(problems(&[]), vec![(0, 0, Problem::NoScripts)]);
+    }
+}
@@ -1,25 +1,150 @@
 extern crate bytes;
 
 use self::bytes::{Bytes, BytesMut};
-use vm_script::VMScript;
+use arith::OverflowMode;
+use snapshot::{Snapshot, SnapshotError};
+use std::error::Error;
+use std::fmt;
+use tracer::{NoTracer, Tracer};
+use verifier::{verify, Diagnostic};
+use vm_error::VmError;
+use vm_script::{ExitStatus, VMScript};
+
+/// Deepest CAL nesting allowed unless configured otherwise
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+/// Execution limits shared by every script run by a `VM`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VmConfig {
+    pub max_call_depth: usize,
+    pub overflow: OverflowMode,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            overflow: OverflowMode::Wrapping,
+        }
+    }
+}
+
+/// Why `VM::resume` could not carry on
+#[derive(Debug, PartialEq, Clone)]
+pub enum ResumeError {
+    // The saved state does not fit the scripts or the configuration
+    Snapshot(SnapshotError),
+    Vm(VmError),
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResumeError::Snapshot(ref e) => write!(f, "cannot resume: {}", e),
+            ResumeError::Vm(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ResumeError {}
+
+impl From<SnapshotError> for ResumeError {
+    fn from(e: SnapshotError) -> ResumeError {
+        ResumeError::Snapshot(e)
+    }
+}
+
+impl From<VmError> for ResumeError {
+    fn from(e: VmError) -> ResumeError {
+        ResumeError::Vm(e)
+    }
+}
 
 //#[derive(Debug)]
 pub struct VM<'a> {
     scripts: &'a [Bytes],
+    config: VmConfig,
     pub heap: BytesMut,
+    // Where `resume` carries on from, None to start from the entry script
+    state: Option<Snapshot>,
 }
 
 impl<'a> VM<'a> {
-    pub fn new(scripts: &'a [Bytes]) -> VM {
-        VM {
+    pub fn new(scripts: &'a [Bytes]) -> Result<VM<'a>, VmError> {
+        VM::with_config(scripts, VmConfig::default())
+    }
+
+    /// Fails with `VmError::NoScripts` if `scripts` has no entry script
+    pub fn with_config(scripts: &'a [Bytes], config: VmConfig) -> Result<VM<'a>, VmError> {
+        if scripts.is_empty() {
+            return Err(VmError::NoScripts);
+        }
+        Ok(VM {
             scripts,
+            config,
             heap: BytesMut::with_capacity(0xFF),
+            state: None,
+        })
+    }
+
+    /// Like `with_config`, but the scripts are first checked with `verify`, so malformed
+    /// bytecode is rejected before any gas is spent
+    pub fn verified(scripts: &'a [Bytes], config: VmConfig) -> Result<VM<'a>, Vec<Diagnostic>> {
+        let diags = verify(scripts);
+        if !diags.is_empty() {
+            return Err(diags);
         }
+        // `verify` rejects an empty library, so there is an entry script
+        Ok(VM {
+            scripts,
+            config,
+            heap: BytesMut::with_capacity(0xFF),
+            state: None,
+        })
+    }
+
+    /// A machine that `resume` carries on from `snapshot`
+    pub fn from_snapshot(
+        scripts: &'a [Bytes],
+        config: VmConfig,
+        snapshot: &Snapshot,
+    ) -> Result<VM<'a>, SnapshotError> {
+        // No snapshot matches an empty library
+        let mut vm = VM::with_config(scripts, config).map_err(|_| SnapshotError::ScriptMismatch)?;
+        // Check the snapshot fits the scripts now rather than when resuming
+        VMScript::with_config(scripts, &mut vm.heap, config)
+            .map_err(|_| SnapshotError::ScriptMismatch)?
+            .restore(snapshot)?;
+        vm.state = Some(snapshot.clone());
+        Ok(vm)
+    }
+
+    /// State at the point the last `resume` stopped, or the snapshot it will start from
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.state.as_ref()
+    }
+
+    /// Runs the entry script, spending at most `gas` across it and every script it calls
+    pub fn run(&mut self, gas: u64) -> Result<ExitStatus, VmError> {
+        self.run_with(gas, &mut NoTracer)
+    }
+
+    /// Like `run`, reporting execution to `tracer`
+    pub fn run_with<T: Tracer>(&mut self, gas: u64, tracer: &mut T) -> Result<ExitStatus, VmError> {
+        let mut vm_scr = VMScript::with_config(self.scripts, &mut self.heap, self.config)?;
+        vm_scr.run_with(gas, tracer)
     }
 
-    pub fn run(&mut self) -> bool {
-        let mut vm_scr = VMScript::new(self.scripts, &mut self.heap);
-        vm_scr.run()
+    /// Continues from the snapshot, or from where the previous `resume` stopped, with
+    /// `gas` more to spend. Without a snapshot this starts from the entry script.
+    pub fn resume(&mut self, gas: u64) -> Result<ExitStatus, ResumeError> {
+        let mut vm_scr = VMScript::with_config(self.scripts, &mut self.heap, self.config)?;
+        if let Some(ref state) = self.state {
+            vm_scr.restore(state)?;
+        }
+        let result = vm_scr.resume(gas);
+        self.state = Some(vm_scr.snapshot());
+        Ok(result?)
     }
 }
 
@@ -37,10 +162,153 @@ mod tests {
             Bytes::from(&[Opcode::CAL as u8, 0x0, 0][..]),  // Call script at offset 0
             Bytes::from(&[Opcode::LOD as u8, reg, 0x0, 0x0, 0x0, 0xFF, Opcode::PSH as u8, reg, 0][..]), // Load 0xFF into reg0, push reg0
         ];
-        let mut test_vm = VM::new(script);
-        assert_eq!(test_vm.run(), true);
+        let mut test_vm = VM::new(script).unwrap();
+        assert!(matches!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { .. })));
         assert_eq!(test_vm.heap, Bytes::from(&[0xFF,0xFF,0xFF,0xFF,0xFF,0x0,0x0,0x0][..])); // Verify memory is what it should be
     }
 
-   
+    #[test]
+    fn test_vm_ret() {
+        let script = &[
+            Bytes::from(&[Opcode::CAL as u8, 0x0, Opcode::LOD as u8, 0, 0x0, 0x0, 0x0, 0x02, Opcode::PSH as u8, 0, 0][..]), // Call, then push 0x02 once the callee returns
+            Bytes::from(&[Opcode::LOD as u8, 0, 0x0, 0x0, 0x0, 0x01, Opcode::PSH as u8, 0, Opcode::RET as u8][..]), // Push 0x01 and return to the caller
+        ];
+        let mut test_vm = VM::new(script).unwrap();
+        assert!(matches!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { .. })));
+        assert_eq!(test_vm.heap, Bytes::from(&[0x01, 0x0, 0x0, 0x0, 0x02, 0x0, 0x0, 0x0][..]));
+    }
+
+    #[test]
+    fn test_vm_hlt_in_callee() {
+        let script = &[
+            Bytes::from(&[Opcode::CAL as u8, 0x0, Opcode::PSH as u8, 0, 0][..]), // Never resumed
+            Bytes::from(&[0][..]),
+        ];
+        let mut test_vm = VM::new(script).unwrap();
+        assert!(matches!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { .. })));
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_vm_call_depth() {
+        let script = &[
+            Bytes::from(&[Opcode::CAL as u8, 0x0, 0][..]),
+            Bytes::from(&[Opcode::CAL as u8, 0x0, Opcode::RET as u8][..]),
+            Bytes::from(&[Opcode::CAL as u8, 0x0, Opcode::RET as u8][..]),
+            Bytes::from(&[Opcode::RET as u8][..]),
+        ];
+        let mut test_vm = VM::with_config(script, VmConfig { max_call_depth: 3, ..VmConfig::default() }).unwrap();
+        assert!(matches!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { .. })));
+
+        let mut test_vm = VM::with_config(script, VmConfig { max_call_depth: 2, ..VmConfig::default() }).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::CallDepthExceeded {
+                script: 2,
+                pc: 0,
+                opcode: Opcode::CAL as u8,
+                depth: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_vm_gas() {
+        let script = &[
+            Bytes::from(&[Opcode::CAL as u8, 0x0, Opcode::LOD as u8, 0, 0x0, 0x0, 0x0, 0x02, Opcode::PSH as u8, 0, 0][..]),
+            Bytes::from(&[Opcode::LOD as u8, 0, 0x0, 0x0, 0x0, 0x01, Opcode::PSH as u8, 0, Opcode::RET as u8][..]),
+        ];
+        let mut test_vm = VM::new(script).unwrap();
+        assert_eq!(test_vm.run(28), Ok(ExitStatus::Halted { gas_used: 28 }));
+
+        // Out of gas on the caller's PSH, after the callee has returned
+        let mut test_vm = VM::new(script).unwrap();
+        assert_eq!(test_vm.run(27), Ok(ExitStatus::OutOfGas { gas_used: 22 }));
+        assert_eq!(test_vm.heap, Bytes::from(&[0x01, 0x0, 0x0, 0x0][..]));
+    }
+
+    #[test]
+    fn test_vm_gas_infinite_loop() {
+        let script = &[Bytes::from(&[Opcode::JMP as u8, 0, 0, 0, 0, 0][..])];
+        let mut test_vm = VM::new(script).unwrap();
+        assert_eq!(test_vm.run(101), Ok(ExitStatus::OutOfGas { gas_used: 100 }));
+    }
+
+    #[test]
+    fn test_vm_cal_out_of_bounds() {
+        let script = &[
+            Bytes::from(&[Opcode::CAL as u8, 0x0, 0][..]),
+            Bytes::from(&[Opcode::NOP as u8, Opcode::CAL as u8, 0x1, 0][..]), // Only one script follows this one
+            Bytes::from(&[0][..]),
+        ];
+        let mut test_vm = VM::new(script).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::CallOutOfBounds {
+                script: 1,
+                pc: 1,
+                opcode: Opcode::CAL as u8,
+                index: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_vm_no_scripts() {
+        let scripts: &[Bytes] = &[];
+        assert!(matches!(VM::new(scripts), Err(VmError::NoScripts)));
+        let mut heap = BytesMut::new();
+        assert!(matches!(VMScript::new(scripts, &mut heap), Err(VmError::NoScripts)));
+    }
+
+    #[test]
+    fn test_vm_resume_error() {
+        let script = &[Bytes::from(&[0xFF][..])];
+        let mut test_vm = VM::new(script).unwrap();
+        let e = test_vm.resume(u64::MAX).unwrap_err();
+        assert!(matches!(e, ResumeError::Vm(VmError::UnknownOpcode { opcode: 0xFF, .. })));
+        assert_eq!(e.to_string(), test_vm.run(u64::MAX).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_vm_verified() {
+        use verifier::Problem;
+
+        // The bad opcode comes after a loop that would use up any budget first
+        let script = &[Bytes::from(
+            &[Opcode::JMP as u8, 0, 0, 0, 0, 0, Opcode::HLT as u8, 0xFF][..],
+        )];
+        let diags = VM::verified(script, VmConfig::default()).err().unwrap();
+        assert_eq!(diags.len(), 1);
+        assert_eq!((diags[0].script, diags[0].offset), (0, 7));
+        assert_eq!(diags[0].problem, Problem::UnknownOpcode { opcode: 0xFF });
+        assert_eq!(
+            VM::verified(&[], VmConfig::default()).err().unwrap()[0].problem,
+            Problem::NoScripts
+        );
+
+        let script = &[Bytes::from(&[Opcode::NOP as u8, Opcode::HLT as u8][..])];
+        let mut test_vm = VM::verified(script, VmConfig::default()).ok().unwrap();
+        assert_eq!(test_vm.run(10), Ok(ExitStatus::Halted { gas_used: 1 }));
+    }
+
+    #[test]
+    fn test_vm_resume_after_halt() {
+        // The bytes after the HLT must never run
+        let script = &[Bytes::from(&[Opcode::NOP as u8, Opcode::HLT as u8, 0xFF][..])];
+        let mut test_vm = VM::new(script).unwrap();
+        assert_eq!(test_vm.resume(100), Ok(ExitStatus::Halted { gas_used: 1 }));
+        let halted = test_vm.snapshot().cloned().unwrap();
+        assert!(halted.halted);
+        assert_eq!(test_vm.resume(100), Ok(ExitStatus::Halted { gas_used: 1 }));
+        assert_eq!(test_vm.resume(100), Ok(ExitStatus::Halted { gas_used: 1 }));
+        assert_eq!(test_vm.snapshot(), Some(&halted));
+
+        // Also when carrying on from a saved snapshot
+        let data = halted.to_bytes().unwrap();
+        let snapshot = Snapshot::from_bytes(&data).unwrap();
+        let mut test_vm = VM::from_snapshot(script, VmConfig::default(), &snapshot).unwrap();
+        assert_eq!(test_vm.resume(100), Ok(ExitStatus::Halted { gas_used: 1 }));
+        assert_eq!(test_vm.snapshot().map(|s| s.pc), Some(2));
+    }
 }
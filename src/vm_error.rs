@@ -0,0 +1,177 @@
+use instruction::Opcode;
+use std::error::Error;
+use std::fmt;
+
+/// Errors raised while executing a script.
+/// `script` is the index of the failing script in the slice given to `VM::new`,
+/// `pc` is the offset of the failing instruction and `opcode` its raw byte.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    // The library has no entry script; reported as script 0 pc 0
+    NoScripts,
+    UnknownOpcode {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+    },
+    // Execution ran off the end of the script without a HLT
+    MissingHalt {
+        script: usize,
+        pc: usize,
+    },
+    // The script ended in the middle of an instruction's operands
+    TruncatedOperand {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+    },
+    InvalidRegister {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        reg: u8,
+    },
+    // Register operands from banks the opcode cannot combine
+    WidthMismatch {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        reg1: u8,
+        reg2: u8,
+    },
+    CallOutOfBounds {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        index: usize,
+    },
+    // A CAL would nest deeper than `VmConfig::max_call_depth`
+    CallDepthExceeded {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        depth: usize,
+    },
+    HeapUnderflow {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+    },
+    // Arithmetic overflowed under `OverflowMode::Trapping`
+    Overflow {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+    },
+    // DIV or MOD with a zero divisor, in every overflow mode
+    DivideByZero {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+    },
+    InvalidJumpMode {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        mode: u8,
+    },
+    // Jump target resolved outside the current script
+    InvalidJump {
+        script: usize,
+        pc: usize,
+        opcode: u8,
+        target: i64,
+    },
+}
+
+impl VmError {
+    /// Index of the script that was executing when the error was raised
+    pub fn script(&self) -> usize {
+        match *self {
+            VmError::NoScripts => 0,
+            VmError::UnknownOpcode { script, .. }
+            | VmError::MissingHalt { script, .. }
+            | VmError::TruncatedOperand { script, .. }
+            | VmError::InvalidRegister { script, .. }
+            | VmError::WidthMismatch { script, .. }
+            | VmError::CallOutOfBounds { script, .. }
+            | VmError::CallDepthExceeded { script, .. }
+            | VmError::HeapUnderflow { script, .. }
+            | VmError::Overflow { script, .. }
+            | VmError::DivideByZero { script, .. }
+            | VmError::InvalidJumpMode { script, .. }
+            | VmError::InvalidJump { script, .. } => script,
+        }
+    }
+
+    /// Offset of the failing instruction within its script
+    pub fn pc(&self) -> usize {
+        match *self {
+            VmError::NoScripts => 0,
+            VmError::UnknownOpcode { pc, .. }
+            | VmError::MissingHalt { pc, .. }
+            | VmError::TruncatedOperand { pc, .. }
+            | VmError::InvalidRegister { pc, .. }
+            | VmError::WidthMismatch { pc, .. }
+            | VmError::CallOutOfBounds { pc, .. }
+            | VmError::CallDepthExceeded { pc, .. }
+            | VmError::HeapUnderflow { pc, .. }
+            | VmError::Overflow { pc, .. }
+            | VmError::DivideByZero { pc, .. }
+            | VmError::InvalidJumpMode { pc, .. }
+            | VmError::InvalidJump { pc, .. } => pc,
+        }
+    }
+
+    /// Raw opcode byte of the failing instruction, if one was fetched
+    pub fn opcode(&self) -> Option<u8> {
+        match *self {
+            VmError::NoScripts | VmError::MissingHalt { .. } => None,
+            VmError::UnknownOpcode { opcode, .. }
+            | VmError::TruncatedOperand { opcode, .. }
+            | VmError::InvalidRegister { opcode, .. }
+            | VmError::WidthMismatch { opcode, .. }
+            | VmError::CallOutOfBounds { opcode, .. }
+            | VmError::CallDepthExceeded { opcode, .. }
+            | VmError::HeapUnderflow { opcode, .. }
+            | VmError::Overflow { opcode, .. }
+            | VmError::DivideByZero { opcode, .. }
+            | VmError::InvalidJumpMode { opcode, .. }
+            | VmError::InvalidJump { opcode, .. } => Some(opcode),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "script {} pc {:#x}", self.script(), self.pc())?;
+        if let Some(op) = self.opcode() {
+            write!(f, " ({:?})", Opcode::from(op))?;
+        }
+        match *self {
+            VmError::NoScripts => write!(f, ": no entry script, the library is empty"),
+            VmError::UnknownOpcode { opcode, .. } => write!(f, ": unknown opcode {:#x}", opcode),
+            VmError::MissingHalt { .. } => write!(f, ": program counter overrun, missing 'HLT'?"),
+            VmError::TruncatedOperand { .. } => write!(f, ": script ends inside operands"),
+            VmError::InvalidRegister { reg, .. } => write!(f, ": invalid register {:#x}", reg),
+            VmError::WidthMismatch { reg1, reg2, .. } => {
+                write!(f, ": registers {:#x} and {:#x} have incompatible widths", reg1, reg2)
+            }
+            VmError::CallOutOfBounds { index, .. } => {
+                write!(f, ": cannot call lib with index {}, out of bounds", index)
+            }
+            VmError::CallDepthExceeded { depth, .. } => {
+                write!(f, ": call depth {} exceeds the configured maximum", depth)
+            }
+            VmError::HeapUnderflow { .. } => write!(f, ": attempted to pop more bytes than exist"),
+            VmError::Overflow { .. } => write!(f, ": arithmetic overflow"),
+            VmError::DivideByZero { .. } => write!(f, ": division by zero"),
+            VmError::InvalidJumpMode { mode, .. } => write!(f, ": invalid jump mode {:#x}", mode),
+            VmError::InvalidJump { target, .. } => {
+                write!(f, ": jump target {} is outside the script", target)
+            }
+        }
+    }
+}
+
+impl Error for VmError {}
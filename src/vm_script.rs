@@ -2,475 +2,1169 @@ extern crate byteorder;
 extern crate bytes;
 
 //use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use self::bytes::{BufMut, Bytes, BytesMut};
-use instruction::Opcode;
+use self::bytes::{Bytes, BytesMut};
+use arith::{Outcome, Word};
+use decode::{decode, Arg, DecodeError, Instruction};
+use container::fnv1a;
+use gas;
+use instruction::{JumpMode, Opcode, Operand, RegLocal};
+use std::cmp::Ordering;
+use snapshot::{Snapshot, SnapshotError};
 use std::mem::size_of;
+use tracer::{NoTracer, Tracer};
+use vm::VmConfig;
+use vm_error::VmError;
 
-const REGSIZE: usize = 0xFF / 4;
+pub const REGSIZE: usize = 0xFF / 4;
 
-pub struct VMScript<'a> {
-    pc: usize,  // Program Counter -- will be used as an index, could be u8 otherwise
-    sp: usize,  // Stack Pointer -- although used for the 'heap'
-    f_eq: bool, // is_equal flag
-    f_lt: bool, // lessthan flag
-    f_gt: bool, // greaterthan flag
-    regs32: [i32; REGSIZE],
-    regs64: [i64; REGSIZE],
-    regs128: [i128; REGSIZE],
-    rem32: u32, // Remainder for DIV
-    rem64: u64,
-    rem128: u128,
-    script: &'a Bytes,
-    libs: &'a [Bytes],
-    heap: &'a mut BytesMut,
-}
-#[derive(Debug)]
-enum RegLocal {
-    REG32,
-    REG64,
-    REG128,
+/// How a script finished when it did not raise a `VmError`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitStatus {
+    Halted { gas_used: u64 },
+    // The next instruction would have exceeded the budget, it was not executed
+    OutOfGas { gas_used: u64 },
 }
 
-impl From<u8> for RegLocal {
-    fn from(v: u8) -> Self {
-        let masked = v >> 6; // Top two bits decide which reg
-        match masked {
-            0 => RegLocal::REG32,
-            1 => RegLocal::REG64,
-            2 => RegLocal::REG128,
-            _ => panic!("Invalid register specified! {:?}", v),
+impl ExitStatus {
+    pub fn gas_used(&self) -> u64 {
+        match *self {
+            ExitStatus::Halted { gas_used } | ExitStatus::OutOfGas { gas_used } => gas_used,
         }
     }
 }
 
-impl<'a> VMScript<'a> {
-    pub fn new(libs: &'a [Bytes], heap: &'a mut BytesMut) -> VMScript<'a> {
-        VMScript {
-            pc: 0,
-            sp: 0,
+/// Where execution resumes once a called script returns
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Frame {
+    pub script: usize,
+    pub pc: usize,
+}
+
+/// Comparison flags set by CMP, CMPI and CMPU, and the overflow flag
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Flags {
+    pub eq: bool,
+    pub lt: bool,
+    pub gt: bool,
+    pub of: bool,
+}
+
+/// Heap bytes moved by a PSH or POP, `offset` being where the first of them sits
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeapAccess {
+    Push { offset: usize, bytes: Vec<u8> },
+    Pop { offset: usize, bytes: Vec<u8> },
+}
+
+/// What a single call to `VMScript::step` did
+#[derive(Debug, PartialEq, Clone)]
+pub struct Step {
+    // Index of the script the instruction belongs to
+    pub script: usize,
+    pub instruction: Instruction,
+    // Register operand bytes the instruction wrote, not counting the remainders
+    pub written: Vec<u8>,
+    pub heap: Option<HeapAccess>,
+    // Set once execution has stopped. When out of gas the instruction did not run.
+    pub status: Option<ExitStatus>,
+}
+
+/// Register banks, remainders and flags: the state that carries over when the same
+/// machine runs several scripts, e.g. one REPL line at a time
+#[derive(Debug, PartialEq, Clone)]
+pub struct Registers {
+    pub regs32: [i32; REGSIZE],
+    pub regs64: [i64; REGSIZE],
+    pub regs128: [i128; REGSIZE],
+    pub rem32: i32, // Remainder for DIV, read back with REM
+    pub rem64: i64,
+    pub rem128: i128,
+    pub f_eq: bool, // is_equal flag
+    pub f_lt: bool, // lessthan flag
+    pub f_gt: bool, // greaterthan flag
+    pub f_of: bool, // overflow flag, set by the last ADD/ADDI, SUB/SUBI, MUL/MULI, DIV, INC or SHL
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers {
+            regs32: [0; REGSIZE],
+            regs64: [0; REGSIZE],
+            regs128: [0; REGSIZE],
             rem32: 0,
             rem64: 0,
             rem128: 0,
             f_eq: false,
             f_lt: false,
             f_gt: false,
-            regs32: [0; REGSIZE],
-            regs64: [0; REGSIZE],
-            regs128: [0; REGSIZE],
-            script: &libs[0],
-            libs,
-            heap,
+            f_of: false,
+        }
+    }
+}
+
+impl Registers {
+    /// Value of the register named by an operand byte, widened to i128.
+    /// `None` if the byte selects no bank or an index past `REGSIZE`.
+    pub fn get(&self, reg: u8) -> Option<i128> {
+        let idx = (reg & 0x3F) as usize;
+        if idx >= REGSIZE {
+            return None;
         }
+        match RegLocal::decode(reg)? {
+            RegLocal::REG32 => Some(i128::from(self.regs32[idx])),
+            RegLocal::REG64 => Some(i128::from(self.regs64[idx])),
+            RegLocal::REG128 => Some(self.regs128[idx]),
+        }
+    }
+}
+
+pub struct VMScript<'a> {
+    pc: usize,  // Program Counter -- will be used as an index, could be u8 otherwise
+    sp: usize,  // Stack Pointer -- although used for the 'heap', always its length
+    regs: Registers,
+    op_pc: usize,      // Offset of the instruction currently executing, for error reporting
+    op: u8,            // Opcode byte of the instruction currently executing
+    script_idx: usize, // Index of `script` in `libs`
+    calls: Vec<Frame>, // Return addresses of the scripts waiting on a CAL
+    config: VmConfig,
+    gas_limit: u64,
+    gas_used: u64,
+    halted: bool,      // Set by HLT or the last RET, `resume` runs nothing more until reset
+    script: Bytes,     // Shares its bytes with the entry in `libs`
+    libs: Vec<Bytes>,
+    heap: &'a mut BytesMut,
+}
+impl<'a> VMScript<'a> {
+    pub fn new(libs: &[Bytes], heap: &'a mut BytesMut) -> Result<VMScript<'a>, VmError> {
+        VMScript::with_config(libs, heap, VmConfig::default())
+    }
+
+    /// Fails with `VmError::NoScripts` if `libs` has no entry script
+    pub fn with_config(libs: &[Bytes], heap: &'a mut BytesMut, config: VmConfig) -> Result<VMScript<'a>, VmError> {
+        let script = libs.first().ok_or(VmError::NoScripts)?;
+        Ok(VMScript {
+            pc: 0,
+            sp: heap.len(),
+            regs: Registers::default(),
+            op_pc: 0,
+            op: 0,
+            script_idx: 0,
+            calls: Vec::new(),
+            config,
+            gas_limit: u64::MAX,
+            gas_used: 0,
+            halted: false,
+            script: script.clone(),
+            libs: libs.to_vec(),
+            heap,
+        })
     }
 
+    /// Back to the start of the entry script with zeroed registers and an empty heap
     pub fn reset(&mut self) {
         self.pc = 0;
+        self.heap.clear();
         self.sp = 0;
-        self.rem32 = 0;
-        self.rem64 = 0;
-        self.rem128 = 0;
-        self.f_eq = false;
-        self.f_lt = false;
-        self.f_gt = false;
-        self.regs32 = [0; REGSIZE];
-        self.regs64 = [0; REGSIZE];
-        self.regs128 = [0; REGSIZE];
-    }
-
-    pub fn run(&mut self) -> bool {
-        let mut finished = false;
-        while !finished {
-            finished = !self.step();
+        self.regs = Registers::default();
+        self.script_idx = 0;
+        self.script = self.libs[0].clone();
+        self.calls.clear();
+        self.gas_used = 0;
+        self.halted = false;
+    }
+
+    /// Makes `entry` the entry script, followed by `libs`, and moves to its start.
+    /// Registers, flags and the heap are kept.
+    pub fn set_scripts(&mut self, entry: Bytes, libs: &[Bytes]) {
+        self.libs.clear();
+        self.libs.push(entry.clone());
+        self.libs.extend_from_slice(libs);
+        self.script = entry;
+        self.script_idx = 0;
+        self.pc = 0;
+        self.calls.clear();
+        self.halted = false;
+    }
+
+    /// Runs until HLT, an error, or until `gas` has been spent
+    pub fn run(&mut self, gas: u64) -> Result<ExitStatus, VmError> {
+        self.run_with(gas, &mut NoTracer)
+    }
+
+    /// Like `run`, reporting each instruction, call and heap write to `tracer`
+    pub fn run_with<T: Tracer>(&mut self, gas: u64, tracer: &mut T) -> Result<ExitStatus, VmError> {
+        self.gas_used = 0;
+        self.resume_with(gas, tracer)
+    }
+
+    /// Carries on from the current pc, e.g. after `restore` or running out of gas, with
+    /// `gas` more to spend. The gas used keeps counting from where it was. Once halted
+    /// nothing more runs, and the same `Halted` status is returned again.
+    pub fn resume(&mut self, gas: u64) -> Result<ExitStatus, VmError> {
+        self.resume_with(gas, &mut NoTracer)
+    }
+
+    pub fn resume_with<T: Tracer>(&mut self, gas: u64, tracer: &mut T) -> Result<ExitStatus, VmError> {
+        if self.halted {
+            return Ok(ExitStatus::Halted {
+                gas_used: self.gas_used,
+            });
+        }
+        self.gas_limit = self.gas_used.saturating_add(gas);
+        let result = loop {
+            match self.exec(tracer) {
+                Ok(None) => {}
+                Ok(Some(status)) => break Ok(status),
+                Err(e) => break Err(e),
+            }
+        };
+        tracer.on_halt(&result);
+        result
+    }
+
+    /// Budget for the following calls to `step`, unlimited until set. Resets the gas used.
+    pub fn set_gas(&mut self, gas: u64) {
+        self.gas_limit = gas;
+        self.gas_used = 0;
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    /// Executes one instruction and reports what it did
+    pub fn step(&mut self) -> Result<Step, VmError> {
+        let (script, script_idx, offset, sp) = (self.script.clone(), self.script_idx, self.pc, self.sp);
+        let status = self.exec(&mut NoTracer)?;
+        // Everything the VM read decodes, except when it ran out of gas before reading
+        let instruction = decode(&script, offset).map_err(|e| self.decode_error(script_idx, e))?;
+        let mut step = Step {
+            script: script_idx,
+            instruction,
+            written: vec![],
+            heap: None,
+            status,
+        };
+        if let Some(ExitStatus::OutOfGas { .. }) = status {
+            return Ok(step);
+        }
+        let first = match step.instruction.args.first() {
+            Some(&Arg::Reg(reg)) => reg,
+            _ => return Ok(step),
+        };
+        match step.instruction.opcode {
+            Opcode::CMP | Opcode::CMPI | Opcode::CMPU => {}
+            Opcode::PSH => {
+                step.heap = Some(HeapAccess::Push {
+                    offset: sp,
+                    bytes: self.heap[sp..self.sp].to_vec(),
+                });
+            }
+            Opcode::POP => {
+                // The popped bytes are gone from the heap but now sit in the register
+                let value = self.register(first).unwrap_or(0);
+                step.heap = Some(HeapAccess::Pop {
+                    offset: self.sp,
+                    bytes: value.to_le_bytes()[..sp - self.sp].to_vec(),
+                });
+                step.written.push(first);
+            }
+            _ => step.written.push(first),
+        }
+        Ok(step)
+    }
+
+    /// Copy of the machine's state, including the heap and the pending calls
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            script: self.script_idx,
+            pc: self.pc,
+            registers: self.regs.clone(),
+            heap: self.heap.to_vec(),
+            calls: self.calls.clone(),
+            gas_used: self.gas_used,
+            scripts: self.libs.iter().map(|s| fnv1a(s)).collect(),
+            config: Some(self.config),
+            halted: self.halted,
+        }
+    }
+
+    /// Puts the machine back in the state `snapshot` was taken in. The scripts and the
+    /// configuration must be the ones it was taken with, and are checked if it records them.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        if snapshot.config.is_some_and(|c| c != self.config) {
+            return Err(SnapshotError::ConfigMismatch);
+        }
+        if !snapshot.scripts.is_empty() {
+            let same = snapshot.scripts.len() == self.libs.len()
+                && self.libs.iter().zip(&snapshot.scripts).all(|(s, h)| fnv1a(s) == *h);
+            if !same {
+                return Err(SnapshotError::ScriptMismatch);
+            }
+        }
+        // A pc at the very end is allowed, it fails with MissingHalt on resuming
+        let frames = snapshot.calls.iter().map(|f| (f.script, f.pc));
+        for (script, pc) in frames.chain(Some((snapshot.script, snapshot.pc))) {
+            if self.libs.get(script).is_none_or(|s| pc > s.len()) {
+                return Err(SnapshotError::InvalidPosition { script, pc });
+            }
+        }
+        self.script_idx = snapshot.script;
+        self.script = self.libs[snapshot.script].clone();
+        self.pc = snapshot.pc;
+        self.set_registers(&snapshot.registers);
+        self.heap.clear();
+        self.heap.extend_from_slice(&snapshot.heap);
+        self.sp = self.heap.len();
+        self.calls = snapshot.calls.clone();
+        self.gas_used = snapshot.gas_used;
+        self.halted = snapshot.halted;
+        Ok(())
+    }
+
+    /// Index of the script executing, in the slice the machine was built with
+    pub fn script_index(&self) -> usize {
+        self.script_idx
+    }
+
+    /// Offset of the next instruction in the executing script
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Scripts waiting on a CAL, the innermost last
+    pub fn calls(&self) -> &[Frame] {
+        &self.calls
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        self.heap
+    }
+
+    pub fn regs32(&self) -> &[i32; REGSIZE] {
+        &self.regs.regs32
+    }
+
+    pub fn regs32_mut(&mut self) -> &mut [i32; REGSIZE] {
+        &mut self.regs.regs32
+    }
+
+    pub fn regs64(&self) -> &[i64; REGSIZE] {
+        &self.regs.regs64
+    }
+
+    pub fn regs64_mut(&mut self) -> &mut [i64; REGSIZE] {
+        &mut self.regs.regs64
+    }
+
+    pub fn regs128(&self) -> &[i128; REGSIZE] {
+        &self.regs.regs128
+    }
+
+    pub fn regs128_mut(&mut self) -> &mut [i128; REGSIZE] {
+        &mut self.regs.regs128
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            eq: self.regs.f_eq,
+            lt: self.regs.f_lt,
+            gt: self.regs.f_gt,
+            of: self.regs.f_of,
         }
-        true
     }
 
-    // Expected return value is "should we keep running"
-    fn step(&mut self) -> bool {
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.regs.f_eq = flags.eq;
+        self.regs.f_lt = flags.lt;
+        self.regs.f_gt = flags.gt;
+        self.regs.f_of = flags.of;
+    }
+
+    /// Value of the register named by an operand byte, widened to i128.
+    /// `None` if the byte selects no bank or an index past `REGSIZE`.
+    pub fn register(&self, reg: u8) -> Option<i128> {
+        self.regs.get(reg)
+    }
+
+    /// The register banks, remainders and flags
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    /// Replaces the register banks, remainders and flags
+    pub fn set_registers(&mut self, r: &Registers) {
+        self.regs = r.clone();
+    }
+
+    // Expected return value is None while we should keep running
+    fn exec<T: Tracer>(&mut self, tracer: &mut T) -> Result<Option<ExitStatus>, VmError> {
         // Get opcode from script
-        let o = Opcode::from(self.next_bytes(1)[0]);
-        println!("Opcode found: {:?}", o);
+        self.op_pc = self.pc;
+        self.op = match self.script.get(self.pc) {
+            Some(op) => *op,
+            None => {
+                return Err(VmError::MissingHalt {
+                    script: self.script_idx,
+                    pc: self.pc,
+                })
+            }
+        };
+        self.pc += 1;
+        let o = Opcode::from(self.op);
+
+        // Charge for the instruction before it has any effect
+        let reg = |at: usize| self.script.get(at).and_then(|b| RegLocal::decode(*b));
+        let width = match o {
+            // Conversions are charged for the wider of their two banks
+            Opcode::SEXT | Opcode::ZEXT | Opcode::TRUNC => {
+                reg(self.pc).into_iter().chain(reg(self.pc + 1)).max_by_key(|r| r.size())
+            }
+            _ => match o.operands().first() {
+                Some(Operand::Reg) => reg(self.pc),
+                _ => None,
+            },
+        };
+        let cost = gas::cost(o, width);
+        if self.gas_used.saturating_add(cost) > self.gas_limit {
+            self.pc = self.op_pc;
+            return Ok(Some(ExitStatus::OutOfGas {
+                gas_used: self.gas_used,
+            }));
+        }
+        self.gas_used += cost;
+
+        let script = self.script_idx;
+        tracer.before_instruction(script, self.op_pc, o);
+        let status = self.dispatch(o, tracer)?;
+        if let Some(ExitStatus::Halted { .. }) = status {
+            self.halted = true;
+        }
+        tracer.after_instruction(script, self.op_pc, o, self.gas_used);
+        Ok(status)
+    }
+
+    // Executes the instruction whose opcode has just been read and paid for
+    fn dispatch<T: Tracer>(&mut self, o: Opcode, tracer: &mut T) -> Result<Option<ExitStatus>, VmError> {
         match o {
             Opcode::HLT => {
-                return false;
+                return Ok(Some(ExitStatus::Halted {
+                    gas_used: self.gas_used,
+                }));
             }
             Opcode::NOP => {}
             Opcode::LOD => {
-                let reg = self.next_bytes(1)[0];
-                let r = RegLocal::from(reg);
-                let idx = (reg & 0x3F) as usize;
+                let (r, idx) = self.next_reg()?;
                 match r {
                     RegLocal::REG32 => {
-                        let val = self.read_u32();
-                        self.regs32[idx] = val as i32;
+                        let val = self.read_u32()?;
+                        self.regs.regs32[idx] = val as i32;
                     }
                     RegLocal::REG64 => {
-                        let val = self.read_u64();
-                        self.regs64[idx] = val as i64;
+                        let val = self.read_u64()?;
+                        self.regs.regs64[idx] = val as i64;
                     }
                     RegLocal::REG128 => {
-                        let val = self.read_u128();
-                        self.regs128[idx] = val as i128;
+                        let val = self.read_u128()?;
+                        self.regs.regs128[idx] = val as i128;
                     }
                 }
             }
             Opcode::INC => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                match RegLocal::from(reg) {
+                let (r, idx) = self.next_reg()?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx] += 1;
+                        self.regs.regs32[idx] = self.arith(self.regs.regs32[idx].overflow_add(1))?;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx] += 1;
+                        self.regs.regs64[idx] = self.arith(self.regs.regs64[idx].overflow_add(1))?;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx] += 1;
+                        self.regs.regs128[idx] = self.arith(self.regs.regs128[idx].overflow_add(1))?;
                     }
                 }
             }
-            Opcode::ADD => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::ADD | Opcode::ADDI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::ADDI)?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] += self.regs32[idx2];
+                        self.regs.regs32[idx1] = self.arith(self.regs.regs32[idx1].overflow_add(rhs as i32))?;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] += self.regs64[idx2];
+                        self.regs.regs64[idx1] = self.arith(self.regs.regs64[idx1].overflow_add(rhs as i64))?;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] += self.regs128[idx2];
+                        self.regs.regs128[idx1] = self.arith(self.regs.regs128[idx1].overflow_add(rhs))?;
                     }
                 }
             }
-            Opcode::SUB => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::SUB | Opcode::SUBI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::SUBI)?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] -= self.regs32[idx2];
+                        self.regs.regs32[idx1] = self.arith(self.regs.regs32[idx1].overflow_sub(rhs as i32))?;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] -= self.regs64[idx2];
+                        self.regs.regs64[idx1] = self.arith(self.regs.regs64[idx1].overflow_sub(rhs as i64))?;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] -= self.regs128[idx2];
+                        self.regs.regs128[idx1] = self.arith(self.regs.regs128[idx1].overflow_sub(rhs))?;
                     }
                 }
             }
-            Opcode::MUL => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::MUL | Opcode::MULI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::MULI)?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] *= self.regs32[idx2];
+                        self.regs.regs32[idx1] = self.arith(self.regs.regs32[idx1].overflow_mul(rhs as i32))?;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] *= self.regs64[idx2];
+                        self.regs.regs64[idx1] = self.arith(self.regs.regs64[idx1].overflow_mul(rhs as i64))?;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] *= self.regs128[idx2];
+                        self.regs.regs128[idx1] = self.arith(self.regs.regs128[idx1].overflow_mul(rhs))?;
                     }
                 }
             }
             Opcode::DIV => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+                // Truncating division, the remainder takes the sign of the dividend
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                match r {
                     RegLocal::REG32 => {
-                        self.rem32 = (self.regs32[idx1] % self.regs32[idx2]) as u32;
-                        self.regs32[idx1] /= self.regs32[idx2];
+                        let (a, b) = (self.regs.regs32[idx1], self.regs.regs32[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs32[idx1] = self.arith(a.overflow_div(b))?;
+                        self.regs.rem32 = a.wrapping_rem(b);
                     }
                     RegLocal::REG64 => {
-                        self.rem64 = (self.regs64[idx1] % self.regs64[idx2]) as u64;
-                        self.regs64[idx1] /= self.regs64[idx2];
+                        let (a, b) = (self.regs.regs64[idx1], self.regs.regs64[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs64[idx1] = self.arith(a.overflow_div(b))?;
+                        self.regs.rem64 = a.wrapping_rem(b);
                     }
                     RegLocal::REG128 => {
-                        self.rem128 = (self.regs128[idx1] % self.regs128[idx2]) as u128;
-                        self.regs128[idx1] /= self.regs128[idx2];
+                        let (a, b) = (self.regs.regs128[idx1], self.regs.regs128[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs128[idx1] = self.arith(a.overflow_div(b))?;
+                        self.regs.rem128 = a.wrapping_rem(b);
                     }
                 }
             }
             Opcode::MOD => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+                // Remainder of truncating division, MIN % -1 is 0
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                match r {
+                    RegLocal::REG32 => {
+                        let (a, b) = (self.regs.regs32[idx1], self.regs.regs32[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs32[idx1] = a.wrapping_rem(b);
+                    }
+                    RegLocal::REG64 => {
+                        let (a, b) = (self.regs.regs64[idx1], self.regs.regs64[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs64[idx1] = a.wrapping_rem(b);
+                    }
+                    RegLocal::REG128 => {
+                        let (a, b) = (self.regs.regs128[idx1], self.regs.regs128[idx2]);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs128[idx1] = a.wrapping_rem(b);
+                    }
+                }
+            }
+            Opcode::REM => {
+                // Reads back the remainder left by the last DIV of the register's width
+                let (r, idx) = self.next_reg()?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] %= self.regs32[idx2];
+                        self.regs.regs32[idx] = self.regs.rem32;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] %= self.regs64[idx2];
+                        self.regs.regs64[idx] = self.regs.rem64;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] %= self.regs128[idx2];
+                        self.regs.regs128[idx] = self.regs.rem128;
                     }
                 }
             }
             Opcode::SHR => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                let shft = self.next_bytes(1)[0];
-                match RegLocal::from(reg) {
+                let (r, idx) = self.next_reg()?;
+                let shft = self.next_bytes(1)?[0];
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx] >>= shft;
+                        self.regs.regs32[idx] = self.regs.regs32[idx].shift_right(shft);
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx] >>= shft;
+                        self.regs.regs64[idx] = self.regs.regs64[idx].shift_right(shft);
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx] >>= shft;
+                        self.regs.regs128[idx] = self.regs.regs128[idx].shift_right(shft);
                     }
                 }
             }
             Opcode::SHL => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                let shft = self.next_bytes(1)[0];
-                match RegLocal::from(reg) {
+                let (r, idx) = self.next_reg()?;
+                let shft = self.next_bytes(1)?[0];
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx] <<= shft;
+                        self.regs.regs32[idx] = self.arith(self.regs.regs32[idx].overflow_shl(shft))?;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx] <<= shft;
+                        self.regs.regs64[idx] = self.arith(self.regs.regs64[idx].overflow_shl(shft))?;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx] <<= shft;
+                        self.regs.regs128[idx] = self.arith(self.regs.regs128[idx].overflow_shl(shft))?;
                     }
                 }
             }
-            Opcode::CMP => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::CMP | Opcode::CMPI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::CMPI)?;
+                let ord = match r {
+                    RegLocal::REG32 => self.regs.regs32[idx1].cmp(&(rhs as i32)),
+                    RegLocal::REG64 => self.regs.regs64[idx1].cmp(&(rhs as i64)),
+                    RegLocal::REG128 => self.regs.regs128[idx1].cmp(&rhs),
+                };
+                self.set_cmp_flags(ord);
+            }
+            Opcode::AND | Opcode::ANDI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::ANDI)?;
+                match r {
                     RegLocal::REG32 => {
-                        // Intentionally not done with three statements
-                        // to minimize effective operations
-                        if self.regs32[idx1] == self.regs32[idx2] {
-                            self.f_eq = true;
-                            self.f_gt = false;
-                            self.f_lt = false;
-                        } else if self.regs32[idx1] > self.regs32[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = true;
-                            self.f_lt = false;
-                        } else if self.regs32[idx1] < self.regs32[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = false;
-                            self.f_lt = true;
+                        self.regs.regs32[idx1] &= rhs as i32;
+                    }
+                    RegLocal::REG64 => {
+                        self.regs.regs64[idx1] &= rhs as i64;
+                    }
+                    RegLocal::REG128 => {
+                        self.regs.regs128[idx1] &= rhs;
+                    }
+                }
+            }
+            Opcode::OR | Opcode::ORI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::ORI)?;
+                match r {
+                    RegLocal::REG32 => {
+                        self.regs.regs32[idx1] |= rhs as i32;
+                    }
+                    RegLocal::REG64 => {
+                        self.regs.regs64[idx1] |= rhs as i64;
+                    }
+                    RegLocal::REG128 => {
+                        self.regs.regs128[idx1] |= rhs;
+                    }
+                }
+            }
+            Opcode::NOT => {
+                let (r, idx) = self.next_reg()?;
+                match r {
+                    RegLocal::REG32 => {
+                        self.regs.regs32[idx] = !self.regs.regs32[idx];
+                    }
+                    RegLocal::REG64 => {
+                        self.regs.regs64[idx] = !self.regs.regs64[idx];
+                    }
+                    RegLocal::REG128 => {
+                        self.regs.regs128[idx] = !self.regs.regs128[idx];
+                    }
+                }
+            }
+            Opcode::XOR | Opcode::XORI => {
+                let (r, idx1, rhs) = self.next_binary(o == Opcode::XORI)?;
+                match r {
+                    RegLocal::REG32 => {
+                        self.regs.regs32[idx1] ^= rhs as i32;
+                    }
+                    RegLocal::REG64 => {
+                        self.regs.regs64[idx1] ^= rhs as i64;
+                    }
+                    RegLocal::REG128 => {
+                        self.regs.regs128[idx1] ^= rhs;
+                    }
+                }
+            }
+            Opcode::CMPU => {
+                // Compares the register bits as unsigned
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                let ord = match r {
+                    RegLocal::REG32 => (self.regs.regs32[idx1] as u32).cmp(&(self.regs.regs32[idx2] as u32)),
+                    RegLocal::REG64 => (self.regs.regs64[idx1] as u64).cmp(&(self.regs.regs64[idx2] as u64)),
+                    RegLocal::REG128 => (self.regs.regs128[idx1] as u128).cmp(&(self.regs.regs128[idx2] as u128)),
+                };
+                self.set_cmp_flags(ord);
+            }
+            Opcode::DIVU => {
+                // Unsigned division cannot overflow, the remainder is left for REM
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                match r {
+                    RegLocal::REG32 => {
+                        let (a, b) = (self.regs.regs32[idx1] as u32, self.regs.regs32[idx2] as u32);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
                         }
+                        self.regs.regs32[idx1] = (a / b) as i32;
+                        self.regs.rem32 = (a % b) as i32;
                     }
                     RegLocal::REG64 => {
-                        if self.regs64[idx1] == self.regs64[idx2] {
-                            self.f_eq = true;
-                            self.f_gt = false;
-                            self.f_lt = false;
-                        } else if self.regs64[idx1] > self.regs64[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = true;
-                            self.f_lt = false;
-                        } else if self.regs64[idx1] < self.regs64[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = false;
-                            self.f_lt = true;
+                        let (a, b) = (self.regs.regs64[idx1] as u64, self.regs.regs64[idx2] as u64);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
                         }
+                        self.regs.regs64[idx1] = (a / b) as i64;
+                        self.regs.rem64 = (a % b) as i64;
                     }
                     RegLocal::REG128 => {
-                        if self.regs128[idx1] == self.regs128[idx2] {
-                            self.f_eq = true;
-                            self.f_gt = false;
-                            self.f_lt = false;
-                        } else if self.regs128[idx1] > self.regs128[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = true;
-                            self.f_lt = false;
-                        } else if self.regs128[idx1] < self.regs128[idx2] {
-                            self.f_eq = false;
-                            self.f_gt = false;
-                            self.f_lt = true;
+                        let (a, b) = (self.regs.regs128[idx1] as u128, self.regs.regs128[idx2] as u128);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
                         }
+                        self.regs.regs128[idx1] = (a / b) as i128;
+                        self.regs.rem128 = (a % b) as i128;
                     }
                 }
             }
-            Opcode::AND => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::MODU => {
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] &= self.regs32[idx2];
+                        let (a, b) = (self.regs.regs32[idx1] as u32, self.regs.regs32[idx2] as u32);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs32[idx1] = (a % b) as i32;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] &= self.regs64[idx2];
+                        let (a, b) = (self.regs.regs64[idx1] as u64, self.regs.regs64[idx2] as u64);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs64[idx1] = (a % b) as i64;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] &= self.regs128[idx2];
+                        let (a, b) = (self.regs.regs128[idx1] as u128, self.regs.regs128[idx2] as u128);
+                        if b == 0 {
+                            return Err(self.divide_by_zero());
+                        }
+                        self.regs.regs128[idx1] = (a % b) as i128;
                     }
                 }
             }
-            Opcode::OR => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::SHRU => {
+                // Logical shift, shifting by the width or more leaves zero
+                let (r, idx) = self.next_reg()?;
+                let shft = u32::from(self.next_bytes(1)?[0]);
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] |= self.regs32[idx2];
+                        self.regs.regs32[idx] = (self.regs.regs32[idx] as u32).checked_shr(shft).unwrap_or(0) as i32;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] |= self.regs64[idx2];
+                        self.regs.regs64[idx] = (self.regs.regs64[idx] as u64).checked_shr(shft).unwrap_or(0) as i64;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] |= self.regs128[idx2];
+                        self.regs.regs128[idx] = (self.regs.regs128[idx] as u128).checked_shr(shft).unwrap_or(0) as i128;
                     }
                 }
             }
-            Opcode::NOT => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                match RegLocal::from(reg) {
+            Opcode::MOV => {
+                let (r, idx1, idx2) = self.next_reg_pair()?;
+                match r {
                     RegLocal::REG32 => {
-                        self.regs32[idx] = !self.regs32[idx];
+                        self.regs.regs32[idx1] = self.regs.regs32[idx2];
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx] = !self.regs64[idx];
+                        self.regs.regs64[idx1] = self.regs.regs64[idx2];
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx] = !self.regs128[idx];
+                        self.regs.regs128[idx1] = self.regs.regs128[idx2];
                     }
                 }
             }
-            Opcode::XOR => {
-                let reg1 = self.next_bytes(1)[0];
-                let reg2 = self.next_bytes(1)[0];
-                let idx1 = (reg1 & 0x3F) as usize;
-                let idx2 = (reg2 & 0x3F) as usize;
-                match RegLocal::from(reg1) {
+            Opcode::SEXT | Opcode::ZEXT | Opcode::TRUNC => {
+                // Moves between banks, the destination comes first
+                let (r1, idx1) = self.next_reg()?;
+                let (r2, idx2) = self.next_reg()?;
+                let valid = if o == Opcode::TRUNC {
+                    r1.size() < r2.size()
+                } else {
+                    r1.size() > r2.size()
+                };
+                if !valid {
+                    return Err(self.width_mismatch());
+                }
+                let mut val = self.reg_value(r2, idx2);
+                if o == Opcode::ZEXT {
+                    // Only 32 and 64-bit sources can be widened
+                    val &= (1i128 << (r2.size() * 8)) - 1;
+                }
+                // Narrowing keeps the low bits
+                match r1 {
                     RegLocal::REG32 => {
-                        self.regs32[idx1] ^= self.regs32[idx2];
+                        self.regs.regs32[idx1] = val as i32;
                     }
                     RegLocal::REG64 => {
-                        self.regs64[idx1] ^= self.regs64[idx2];
+                        self.regs.regs64[idx1] = val as i64;
                     }
                     RegLocal::REG128 => {
-                        self.regs128[idx1] ^= self.regs128[idx2];
+                        self.regs.regs128[idx1] = val;
                     }
                 }
             }
 
             Opcode::CAL => {
                 // We're going to pass off execution to another script
-                // Get the index as the next param
-                let idx = (self.next_bytes(1)[0] + 1) as usize;
+                // Get the index as the next param, relative to the script after this one
+                let idx = self.next_bytes(1)?[0] as usize;
+                let target = self.script_idx + 1 + idx;
                 // Call that script if it exists
-                if idx > self.libs.len() - 1 {
-                    panic!("Cannot call lib with index {}, out of bounds!", idx - 1);
+                if target >= self.libs.len() {
+                    return Err(VmError::CallOutOfBounds {
+                        script: self.script_idx,
+                        pc: self.op_pc,
+                        opcode: self.op,
+                        index: idx,
+                    });
+                }
+                if self.calls.len() >= self.config.max_call_depth {
+                    return Err(VmError::CallDepthExceeded {
+                        script: self.script_idx,
+                        pc: self.op_pc,
+                        opcode: self.op,
+                        depth: self.calls.len(),
+                    });
+                }
+                // The caller resumes at the instruction after this CAL
+                self.calls.push(Frame {
+                    script: self.script_idx,
+                    pc: self.pc,
+                });
+                tracer.on_call(self.script_idx, self.op_pc, target);
+                self.script_idx = target;
+                self.script = self.libs[target].clone();
+                self.pc = 0;
+            }
+            Opcode::RET => {
+                // Returning from the entry script ends execution like HLT
+                match self.calls.pop() {
+                    Some(frame) => {
+                        self.script_idx = frame.script;
+                        self.script = self.libs[frame.script].clone();
+                        self.pc = frame.pc;
+                    }
+                    None => {
+                        return Ok(Some(ExitStatus::Halted {
+                            gas_used: self.gas_used,
+                        }))
+                    }
                 }
-                let mut cal_script = VMScript::new(&self.libs[idx..], self.heap);
-                return cal_script.run();
             }
             Opcode::PSH => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                match RegLocal::from(reg) {
+                // Registers are pushed little-endian onto the end of the heap
+                let (r, idx) = self.next_reg()?;
+                match r {
                     RegLocal::REG32 => {
-                        let sz = size_of::<u32>();
-                        for i in 0..sz {
-                            println!("Size of heap is {}", self.heap.len());
-                            self.heap.put((self.regs32[idx] >> (i * 8)) as u8);
-                            println!("{:X}", (self.regs32[idx] >> (i * 8)) as u8);
-                        }
-                        self.sp += sz;
+                        self.heap.extend_from_slice(&self.regs.regs32[idx].to_le_bytes());
+                    }
+                    RegLocal::REG64 => {
+                        self.heap.extend_from_slice(&self.regs.regs64[idx].to_le_bytes());
+                    }
+                    RegLocal::REG128 => {
+                        self.heap.extend_from_slice(&self.regs.regs128[idx].to_le_bytes());
                     }
-                    RegLocal::REG64 => {}
-                    RegLocal::REG128 => {}
                 }
+                tracer.on_heap_write(self.sp, &self.heap[self.sp..]);
+                self.sp += r.size();
             }
             Opcode::POP => {
-                let reg = self.next_bytes(1)[0];
-                let idx = (reg & 0x3F) as usize;
-                match RegLocal::from(reg) {
+                // Overwrites the register with the last bytes pushed and drops them from the heap
+                let (r, idx) = self.next_reg()?;
+                let sz = r.size();
+                if self.sp < sz {
+                    return Err(VmError::HeapUnderflow {
+                        script: self.script_idx,
+                        pc: self.op_pc,
+                        opcode: self.op,
+                    });
+                }
+                let start = self.sp - sz;
+                match r {
                     RegLocal::REG32 => {
-                        let sz = size_of::<u32>();
-                        if self.sp < sz {
-                            panic!("Attempted to pop more bytes than exist!");
-                        }
-                        let (_, heap_val) = self.heap.split_at(self.sp - sz);
-                        for i in 0..(sz) {
-                            self.regs32[idx] += (heap_val[i] as i32) << (i * 8);
-                            println!("{} {:X} += {:X}", i, self.regs32[idx], heap_val[i]);
-                        }
-                        self.sp -= sz;
+                        let mut val = [0; 4];
+                        val.copy_from_slice(&self.heap[start..self.sp]);
+                        self.regs.regs32[idx] = i32::from_le_bytes(val);
+                    }
+                    RegLocal::REG64 => {
+                        let mut val = [0; 8];
+                        val.copy_from_slice(&self.heap[start..self.sp]);
+                        self.regs.regs64[idx] = i64::from_le_bytes(val);
+                    }
+                    RegLocal::REG128 => {
+                        let mut val = [0; 16];
+                        val.copy_from_slice(&self.heap[start..self.sp]);
+                        self.regs.regs128[idx] = i128::from_le_bytes(val);
                     }
-                    RegLocal::REG64 => {}
-                    RegLocal::REG128 => {}
+                }
+                self.heap.truncate(start);
+                self.sp = start;
+            }
+            Opcode::JMP
+            | Opcode::JEQ
+            | Opcode::JNE
+            | Opcode::JLT
+            | Opcode::JGT
+            | Opcode::JLE
+            | Opcode::JGE => {
+                // The target is validated even when the branch is not taken
+                let target = self.next_target()?;
+                let taken = match o {
+                    Opcode::JMP => true,
+                    Opcode::JEQ => self.regs.f_eq,
+                    Opcode::JNE => !self.regs.f_eq,
+                    Opcode::JLT => self.regs.f_lt,
+                    Opcode::JGT => self.regs.f_gt,
+                    Opcode::JLE => self.regs.f_lt || self.regs.f_eq,
+                    _ => self.regs.f_gt || self.regs.f_eq,
+                };
+                if taken {
+                    self.pc = target;
                 }
             }
             _ => {
-                panic!("Unknown opcode! {:?}", o);
+                return Err(VmError::UnknownOpcode {
+                    script: self.script_idx,
+                    pc: self.op_pc,
+                    opcode: self.op,
+                });
+            }
+        }
+        Ok(None)
+    }
+
+    // Records the overflow flag and picks the result for the configured `OverflowMode`
+    fn arith<T>(&mut self, out: Outcome<T>) -> Result<T, VmError> {
+        self.regs.f_of = out.overflow;
+        match out.resolve(self.config.overflow) {
+            Some(val) => Ok(val),
+            None => Err(VmError::Overflow {
+                script: self.script_idx,
+                pc: self.op_pc,
+                opcode: self.op,
+            }),
+        }
+    }
+
+    // The error executing an instruction that failed to decode raises
+    fn decode_error(&self, script: usize, e: DecodeError) -> VmError {
+        let opcode = |pc: usize| self.libs[script][pc];
+        match e {
+            DecodeError::UnknownOpcode { offset, opcode } => VmError::UnknownOpcode {
+                script,
+                pc: offset,
+                opcode,
+            },
+            DecodeError::Truncated { offset } if offset >= self.libs[script].len() => {
+                VmError::MissingHalt { script, pc: offset }
             }
+            DecodeError::Truncated { offset } => VmError::TruncatedOperand {
+                script,
+                pc: offset,
+                opcode: opcode(offset),
+            },
+            DecodeError::InvalidRegister { offset, reg } => VmError::InvalidRegister {
+                script,
+                pc: offset,
+                opcode: opcode(offset),
+                reg,
+            },
+            DecodeError::InvalidJumpMode { offset, mode } => VmError::InvalidJumpMode {
+                script,
+                pc: offset,
+                opcode: opcode(offset),
+                mode,
+            },
         }
-        true
     }
 
-    fn next_bytes(&mut self, numbytes: usize) -> Bytes {
+    fn divide_by_zero(&self) -> VmError {
+        VmError::DivideByZero {
+            script: self.script_idx,
+            pc: self.op_pc,
+            opcode: self.op,
+        }
+    }
+
+    fn next_bytes(&mut self, numbytes: usize) -> Result<Bytes, VmError> {
+        if self.pc + numbytes > self.script.len() {
+            return Err(VmError::TruncatedOperand {
+                script: self.script_idx,
+                pc: self.op_pc,
+                opcode: self.op,
+            });
+        }
         self.pc += numbytes;
-        if self.pc > self.script.len() {
-            panic!("Program counter overrun! Are you missing a 'HLT'?");
+        Ok(self.script.slice(self.pc - numbytes, self.pc))
+    }
+
+    // Reads a register operand, returning its bank and index within that bank
+    fn next_reg(&mut self) -> Result<(RegLocal, usize), VmError> {
+        let reg = self.next_bytes(1)?[0];
+        let idx = (reg & 0x3F) as usize;
+        match RegLocal::decode(reg) {
+            Some(r) if idx < REGSIZE => Ok((r, idx)),
+            _ => Err(VmError::InvalidRegister {
+                script: self.script_idx,
+                pc: self.op_pc,
+                opcode: self.op,
+                reg,
+            }),
+        }
+    }
+
+    // Reads a jump operand and resolves it to an offset within the current script
+    fn next_target(&mut self) -> Result<usize, VmError> {
+        let mode = self.next_bytes(1)?[0];
+        let raw = self.read_u32()?;
+        let target = match JumpMode::decode(mode) {
+            Some(JumpMode::Absolute) => i64::from(raw),
+            Some(JumpMode::Relative) => self.op_pc as i64 + i64::from(raw as i32),
+            None => {
+                return Err(VmError::InvalidJumpMode {
+                    script: self.script_idx,
+                    pc: self.op_pc,
+                    opcode: self.op,
+                    mode,
+                })
+            }
+        };
+        if target < 0 || target as usize >= self.script.len() {
+            return Err(VmError::InvalidJump {
+                script: self.script_idx,
+                pc: self.op_pc,
+                opcode: self.op,
+                target,
+            });
+        }
+        Ok(target as usize)
+    }
+
+    // Reads the destination register of a binary op and its second operand, either a
+    // register from the same bank or an immediate as wide as the destination.
+    // The second operand is returned sign-extended to 128 bits.
+    fn next_binary(&mut self, imm: bool) -> Result<(RegLocal, usize, i128), VmError> {
+        if !imm {
+            let (r, idx1, idx2) = self.next_reg_pair()?;
+            return Ok((r, idx1, self.reg_value(r, idx2)));
         }
-        self.script.slice(self.pc - numbytes, self.pc)
+        let (r, idx) = self.next_reg()?;
+        let rhs = match r {
+            RegLocal::REG32 => i128::from(self.read_u32()? as i32),
+            RegLocal::REG64 => i128::from(self.read_u64()? as i64),
+            RegLocal::REG128 => self.read_u128()? as i128,
+        };
+        Ok((r, idx, rhs))
     }
 
-    fn read_u32(&mut self) -> u32 {
+    fn set_cmp_flags(&mut self, ord: Ordering) {
+        // Intentionally not done with three statements
+        // to minimize effective operations
+        match ord {
+            Ordering::Equal => {
+                self.regs.f_eq = true;
+                self.regs.f_gt = false;
+                self.regs.f_lt = false;
+            }
+            Ordering::Greater => {
+                self.regs.f_eq = false;
+                self.regs.f_gt = true;
+                self.regs.f_lt = false;
+            }
+            Ordering::Less => {
+                self.regs.f_eq = false;
+                self.regs.f_gt = false;
+                self.regs.f_lt = true;
+            }
+        }
+    }
+
+    // Reads the two register operands of a binary op, which must come from the same bank
+    fn next_reg_pair(&mut self) -> Result<(RegLocal, usize, usize), VmError> {
+        let (r1, idx1) = self.next_reg()?;
+        let (r2, idx2) = self.next_reg()?;
+        if r1 != r2 {
+            return Err(self.width_mismatch());
+        }
+        Ok((r1, idx1, idx2))
+    }
+
+    // Error for the two register operands just read
+    fn width_mismatch(&self) -> VmError {
+        VmError::WidthMismatch {
+            script: self.script_idx,
+            pc: self.op_pc,
+            opcode: self.op,
+            reg1: self.script[self.pc - 2],
+            reg2: self.script[self.pc - 1],
+        }
+    }
+
+    // Value of a register, sign-extended to 128 bits
+    fn reg_value(&self, r: RegLocal, idx: usize) -> i128 {
+        match r {
+            RegLocal::REG32 => i128::from(self.regs.regs32[idx]),
+            RegLocal::REG64 => i128::from(self.regs.regs64[idx]),
+            RegLocal::REG128 => self.regs.regs128[idx],
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, VmError> {
         let sz = size_of::<u32>();
-        let b = self.next_bytes(sz);
+        let b = self.next_bytes(sz)?;
         let mut val = u32::from(b[sz - 1]);
         for (i, v) in b.iter().enumerate().take(sz - 1) {
             val += (u32::from(*v)) << ((sz - 1 - i) * 8);
         }
-        val
+        Ok(val)
     }
 
-    fn read_u64(&mut self) -> u64 {
+    fn read_u64(&mut self) -> Result<u64, VmError> {
         let sz = size_of::<u64>();
-        let b = self.next_bytes(sz);
+        let b = self.next_bytes(sz)?;
         let mut val = u64::from(b[sz - 1]);
         for (i, v) in b.iter().enumerate().take(sz - 1) {
             val += (u64::from(*v)) << ((sz - 1 - i) * 8);
         }
-        val
+        Ok(val)
     }
 
-    fn read_u128(&mut self) -> u128 {
+    fn read_u128(&mut self) -> Result<u128, VmError> {
         let sz = size_of::<u128>();
-        let b = self.next_bytes(sz);
+        let b = self.next_bytes(sz)?;
         let mut val = u128::from(b[sz - 1]);
         for (i, v) in b.iter().enumerate().take(sz - 1) {
             val += (u128::from(*v)) << ((sz - 1 - i) * 8);
         }
-        val
+        Ok(val)
     }
 }
 
+
 #[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
     #![allow(unused_parens)]
     #![allow(overflowing_literals)]
+    #![allow(clippy::identity_op, clippy::unnecessary_cast)]
     use super::*;
+    use arith::OverflowMode;
 
     #[test]
     fn test_heap_32() {
@@ -478,34 +1172,146 @@ mod tests {
         let script = Bytes::from(
             &[
                 Opcode::LOD as u8,
-                reg,
-                0x0F,
+                reg,
+                0x0F,
+                0xFF,
+                0xFF,
+                0xFF,
+                Opcode::PSH as u8,
+                reg,
+                Opcode::PSH as u8,
+                reg,
+                Opcode::LOD as u8,
+                reg,
+                0,
+                0,
+                0,
+                0,
+                Opcode::POP as u8,
+                reg,
+                Opcode::POP as u8,
+                reg + 1,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert!(matches!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { .. })));
+        assert_eq!(test_vm.regs.regs32[reg as usize], 0x0FFFFFFF);
+        assert_eq!(test_vm.regs.regs32[(reg + 1) as usize], 0x0FFFFFFF);
+    }
+
+    #[test]
+    fn test_heap_64() {
+        let reg = (1 << 6) + 1;
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                reg,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFE,
+                Opcode::PSH as u8,
+                reg,
+                Opcode::INC as u8,
+                reg,
+                Opcode::POP as u8,
+                reg,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[1], -2);
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_heap_128() {
+        let reg1 = (1 << 7);
+        let reg2 = (1 << 7) + 1;
+        let mut script = vec![Opcode::LOD as u8, reg1, 0x80];
+        script.extend_from_slice(&[0; 14]);
+        script.push(0x01);
+        script.extend_from_slice(&[Opcode::PSH as u8, reg1, Opcode::PSH as u8, reg1, Opcode::POP as u8, reg2, 0]);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[1], i128::MIN + 1);
+        // One copy is still on the heap, little-endian
+        assert_eq!(test_vm.heap.len(), 16);
+        assert_eq!(test_vm.heap[0], 0x01);
+        assert_eq!(test_vm.heap[15], 0x80);
+    }
+
+    #[test]
+    fn test_heap_mixed_widths() {
+        // Push a 32-bit then a 64-bit value, pop them back in reverse order into other registers
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                0,
                 0xFF,
                 0xFF,
                 0xFF,
+                0x9C,
+                Opcode::LOD as u8,
+                (1 << 6),
+                0x12,
+                0x34,
+                0x56,
+                0x78,
+                0x9A,
+                0xBC,
+                0xDE,
+                0xF0,
                 Opcode::PSH as u8,
-                reg,
+                0,
                 Opcode::PSH as u8,
-                reg,
+                (1 << 6),
                 Opcode::LOD as u8,
-                reg,
-                0,
+                1,
                 0,
                 0,
                 0,
+                7,
                 Opcode::POP as u8,
-                reg,
+                (1 << 6) + 1,
                 Opcode::POP as u8,
-                reg + 1,
+                1,
                 0,
             ][..],
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        assert!(test_vm.run());
-        assert_eq!(test_vm.regs32[reg as usize], 0x0FFFFFFF);
-        assert_eq!(test_vm.regs32[(reg + 1) as usize], 0x0FFFFFFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[1], 0x123456789ABCDEF0);
+        assert_eq!(test_vm.regs.regs32[1], -100);
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_heap_grows_past_capacity() {
+        let mut script = vec![];
+        for _ in 0..20 {
+            script.extend_from_slice(&[Opcode::PSH as u8, (1 << 7)]);
+        }
+        script.push(0);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.heap.len(), 20 * 16);
     }
 
     #[test]
@@ -514,9 +1320,9 @@ mod tests {
         let script = Bytes::from(&[Opcode::LOD as u8, reg, 0xFF, 0xFF, 0xFF, 0xFF, 0][..]);
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[1], -1);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[1], -1);
     }
 
     #[test]
@@ -538,9 +1344,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[1], 0xFFFFFFFF >> 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[1], 0xFFFFFFFF >> 3);
     }
 
     #[test]
@@ -562,9 +1368,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[1], 0xFFFFFFFF << 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[1], 0xFFFFFFFF << 3);
     }
 
     #[test]
@@ -587,9 +1393,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[1], -1);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[1], -1);
     }
 
     #[test]
@@ -615,9 +1421,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[1], 0xFFFFFFFFFFFFFFFF >> 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[1], 0xFFFFFFFFFFFFFFFF >> 3);
     }
 
     #[test]
@@ -643,9 +1449,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[1], 0xFFFFFFFFFFFFFFFF << 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[1], 0xFFFFFFFFFFFFFFFF << 3);
     }
 
     #[test]
@@ -676,9 +1482,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[1], -1);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[1], -1);
     }
 
     #[test]
@@ -712,9 +1518,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[1], (-1 as i128) >> 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[1], (-1 as i128) >> 3);
     }
 
     #[test]
@@ -748,9 +1554,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[1], (-1 as i128) << 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[1], (-1 as i128) << 3);
     }
 
     #[test]
@@ -779,9 +1585,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 1 + 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 1 + 100);
     }
 
     #[test]
@@ -810,9 +1616,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 1 - 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 1 - 100);
     }
 
     #[test]
@@ -841,9 +1647,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 2 * 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 2 * 100);
     }
 
     #[test]
@@ -872,10 +1678,10 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 100 / 3);
-        assert_eq!(test_vm.rem32, 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 100 / 3);
+        assert_eq!(test_vm.regs.rem32, 100 % 3);
     }
 
     #[test]
@@ -904,9 +1710,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 100 % 3);
     }
 
     #[test]
@@ -935,9 +1741,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 100 & 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 100 & 3);
     }
 
     #[test]
@@ -966,9 +1772,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 100 | 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 100 | 3);
     }
 
     #[test]
@@ -997,9 +1803,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], 100 ^ 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], 100 ^ 3);
     }
 
     #[test]
@@ -1020,9 +1826,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], !100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], !100);
     }
 
     #[test]
@@ -1057,9 +1863,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs32[reg1 as usize], (100 / 3) * 3 - 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[reg1 as usize], (100 / 3) * 3 - 3);
     }
 
     #[test]
@@ -1096,9 +1902,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[(reg1 & 0x3F) as usize], 1 + 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[(reg1 & 0x3F) as usize], 1 + 100);
     }
 
     #[test]
@@ -1135,9 +1941,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[0], 1 - 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[0], 1 - 100);
     }
 
     #[test]
@@ -1174,9 +1980,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[(reg1 & 0x3F) as usize], 2 * 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[(reg1 & 0x3F) as usize], 2 * 100);
     }
 
     #[test]
@@ -1215,10 +2021,10 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[(reg1 & 0x3F) as usize], 100 / 3);
-        assert_eq!(test_vm.rem64, 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[(reg1 & 0x3F) as usize], 100 / 3);
+        assert_eq!(test_vm.regs.rem64, 100 % 3);
     }
 
     #[test]
@@ -1257,9 +2063,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs64[(reg1 & 0x3F) as usize], 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[(reg1 & 0x3F) as usize], 100 % 3);
     }
 
     #[test]
@@ -1312,9 +2118,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[0], 1 + 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[0], 1 + 100);
     }
 
     #[test]
@@ -1367,9 +2173,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[0], 1 - 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[0], 1 - 100);
     }
 
     #[test]
@@ -1422,9 +2228,9 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[0], 2 * 100);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[0], 2 * 100);
     }
 
     #[test]
@@ -1477,10 +2283,10 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[0], 100 / 3);
-        assert_eq!(test_vm.rem128, 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[0], 100 / 3);
+        assert_eq!(test_vm.regs.rem128, 100 % 3);
     }
 
     #[test]
@@ -1533,9 +2339,719 @@ mod tests {
         );
         let script_arr = [script];
         let mut heap = BytesMut::with_capacity(0xFF);
-        let mut test_vm = VMScript::new(&script_arr, &mut heap);
-        test_vm.run();
-        assert_eq!(test_vm.regs128[0], 100 % 3);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs128[0], 100 % 3);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let script_arr = [Bytes::from(&[Opcode::NOP as u8, 0xFE, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::UnknownOpcode {
+                script: 0,
+                pc: 1,
+                opcode: 0xFE
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_halt() {
+        let script_arr = [Bytes::from(&[Opcode::NOP as u8, Opcode::NOP as u8][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(test_vm.run(u64::MAX), Err(VmError::MissingHalt { script: 0, pc: 2 }));
+    }
+
+    #[test]
+    fn test_truncated_operand() {
+        let script_arr = [Bytes::from(&[Opcode::NOP as u8, Opcode::LOD as u8, 0, 0xFF, 0xFF][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::TruncatedOperand {
+                script: 0,
+                pc: 1,
+                opcode: Opcode::LOD as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        // Top two bits set
+        let script_arr = [Bytes::from(&[Opcode::INC as u8, 0xC0, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::InvalidRegister {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::INC as u8,
+                reg: 0xC0
+            })
+        );
+
+        // Index past the end of the bank
+        let script_arr = [Bytes::from(&[Opcode::INC as u8, 0x3F, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::InvalidRegister {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::INC as u8,
+                reg: 0x3F
+            })
+        );
+    }
+
+    #[test]
+    fn test_pop_underflow() {
+        let script_arr = [Bytes::from(&[Opcode::POP as u8, 0, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::HeapUnderflow {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::POP as u8
+            })
+        );
+    }
+
+    #[test]
+    fn test_jump_loop() {
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                0,
+                0,
+                0,
+                0,
+                0,
+                Opcode::LOD as u8,
+                1,
+                0,
+                0,
+                0,
+                5,
+                Opcode::INC as u8, // offset 12
+                0,
+                Opcode::CMP as u8,
+                0,
+                1,
+                Opcode::JLT as u8, // Back to the INC
+                JumpMode::Relative as u8,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFB,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[0], 5);
+    }
+
+    // Compares a with b then skips an INC of r2 if the branch is taken
+    fn branch_taken(op: Opcode, a: u8, b: u8) -> bool {
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                0,
+                0,
+                0,
+                0,
+                a,
+                Opcode::LOD as u8,
+                1,
+                0,
+                0,
+                0,
+                b,
+                Opcode::CMP as u8,
+                0,
+                1,
+                op as u8,
+                JumpMode::Relative as u8,
+                0,
+                0,
+                0,
+                8,
+                Opcode::INC as u8,
+                2,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        test_vm.regs.regs32[2] == 0
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        assert!(branch_taken(Opcode::JMP, 1, 2));
+        assert!(branch_taken(Opcode::JEQ, 2, 2));
+        assert!(!branch_taken(Opcode::JEQ, 1, 2));
+        assert!(branch_taken(Opcode::JNE, 1, 2));
+        assert!(!branch_taken(Opcode::JNE, 2, 2));
+        assert!(branch_taken(Opcode::JLT, 1, 2));
+        assert!(!branch_taken(Opcode::JLT, 2, 2));
+        assert!(branch_taken(Opcode::JGT, 3, 2));
+        assert!(!branch_taken(Opcode::JGT, 2, 2));
+        assert!(branch_taken(Opcode::JLE, 2, 2));
+        assert!(branch_taken(Opcode::JLE, 1, 2));
+        assert!(!branch_taken(Opcode::JLE, 3, 2));
+        assert!(branch_taken(Opcode::JGE, 2, 2));
+        assert!(branch_taken(Opcode::JGE, 3, 2));
+        assert!(!branch_taken(Opcode::JGE, 1, 2));
+    }
+
+    #[test]
+    fn test_invalid_jump() {
+        // Absolute target one past the end of the script
+        let script_arr = [Bytes::from(&[Opcode::JEQ as u8, JumpMode::Absolute as u8, 0, 0, 0, 7, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::InvalidJump {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::JEQ as u8,
+                target: 7
+            })
+        );
+
+        // Relative target before the start of the script
+        let script_arr = [Bytes::from(&[Opcode::JMP as u8, JumpMode::Relative as u8, 0xFF, 0xFF, 0xFF, 0xFF, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::InvalidJump {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::JMP as u8,
+                target: -1
+            })
+        );
+
+        let script_arr = [Bytes::from(&[Opcode::JMP as u8, 2, 0, 0, 0, 0, 0][..])];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        assert_eq!(
+            test_vm.run(u64::MAX),
+            Err(VmError::InvalidJumpMode {
+                script: 0,
+                pc: 0,
+                opcode: Opcode::JMP as u8,
+                mode: 2
+            })
+        );
+    }
+
+    // Loads i32::MAX into r0 and 1 into r1, then runs `tail`
+    fn run_overflow32(mode: OverflowMode, tail: &[u8]) -> (Result<ExitStatus, VmError>, i32, bool) {
+        let mut script = vec![
+            Opcode::LOD as u8,
+            0,
+            0x7F,
+            0xFF,
+            0xFF,
+            0xFF,
+            Opcode::LOD as u8,
+            1,
+            0,
+            0,
+            0,
+            1,
+        ];
+        script.extend_from_slice(tail);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let config = VmConfig {
+            overflow: mode,
+            ..VmConfig::default()
+        };
+        let mut test_vm = VMScript::with_config(&script_arr, &mut heap, config).unwrap();
+        let res = test_vm.run(u64::MAX);
+        (res, test_vm.regs.regs32[0], test_vm.regs.f_of)
+    }
+
+    #[test]
+    fn test_add_overflow_modes() {
+        let add = [Opcode::ADD as u8, 0, 1, 0];
+        let (res, val, f_of) = run_overflow32(OverflowMode::Wrapping, &add);
+        assert!(res.is_ok());
+        assert_eq!(val, i32::MIN);
+        assert!(f_of);
+
+        let (res, val, f_of) = run_overflow32(OverflowMode::Saturating, &add);
+        assert!(res.is_ok());
+        assert_eq!(val, i32::MAX);
+        assert!(f_of);
+
+        let (res, val, f_of) = run_overflow32(OverflowMode::Trapping, &add);
+        assert_eq!(
+            res,
+            Err(VmError::Overflow {
+                script: 0,
+                pc: 12,
+                opcode: Opcode::ADD as u8
+            })
+        );
+        assert_eq!(val, i32::MAX);
+        assert!(f_of);
+    }
+
+    #[test]
+    fn test_overflow_flag_cleared() {
+        // The ADD does not overflow, so clears the flag the INC set
+        let tail = [Opcode::INC as u8, 0, Opcode::ADD as u8, 0, 1, 0];
+        let (res, val, f_of) = run_overflow32(OverflowMode::Wrapping, &tail);
+        assert!(res.is_ok());
+        assert_eq!(val, i32::MIN + 1);
+        assert!(!f_of);
+    }
+
+    #[test]
+    fn test_mul_shl_overflow() {
+        let mul = [Opcode::MUL as u8, 0, 0, 0];
+        let (_, val, f_of) = run_overflow32(OverflowMode::Saturating, &mul);
+        assert_eq!(val, i32::MAX);
+        assert!(f_of);
+
+        let shl = [Opcode::SHL as u8, 0, 1, 0];
+        let (_, val, f_of) = run_overflow32(OverflowMode::Wrapping, &shl);
+        assert_eq!(val, -2);
+        assert!(f_of);
+
+        let shl = [Opcode::SHL as u8, 1, 40, 0];
+        let (res, _, _) = run_overflow32(OverflowMode::Trapping, &shl);
+        assert!(res.is_err());
+
+        // Shifting right by more than the width leaves the sign
+        let shr = [Opcode::SHR as u8, 0, 200, 0];
+        let (res, val, _) = run_overflow32(OverflowMode::Trapping, &shr);
+        assert!(res.is_ok());
+        assert_eq!(val, 0);
+    }
+
+    fn bank_bits(bank: RegLocal) -> u8 {
+        match bank {
+            RegLocal::REG32 => 0,
+            RegLocal::REG64 => 1 << 6,
+            RegLocal::REG128 => 2 << 6,
+        }
+    }
+
+    // A script that loads a into r0 and b into r1 of `bank`
+    fn load_pair(bank: RegLocal, a: i128, b: i128) -> Vec<u8> {
+        let top = bank_bits(bank);
+        let sz = bank.size();
+        let mut script = vec![Opcode::LOD as u8, top];
+        script.extend_from_slice(&a.to_be_bytes()[16 - sz..]);
+        script.extend_from_slice(&[Opcode::LOD as u8, top + 1]);
+        script.extend_from_slice(&b.to_be_bytes()[16 - sz..]);
+        script
+    }
+
+    // Loads a into r0 and b into r1 of `bank`, applies `op`, then reads the remainder into r2
+    fn run_div(bank: RegLocal, op: Opcode, a: i128, b: i128, mode: OverflowMode) -> (Result<ExitStatus, VmError>, i128, i128) {
+        let top = bank_bits(bank);
+        let mut script = load_pair(bank, a, b);
+        script.extend_from_slice(&[op as u8, top, top + 1, Opcode::REM as u8, top + 2, 0]);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let config = VmConfig {
+            overflow: mode,
+            ..VmConfig::default()
+        };
+        let mut test_vm = VMScript::with_config(&script_arr, &mut heap, config).unwrap();
+        let res = test_vm.run(u64::MAX);
+        match bank {
+            RegLocal::REG32 => (res, test_vm.regs.regs32[0].into(), test_vm.regs.regs32[2].into()),
+            RegLocal::REG64 => (res, test_vm.regs.regs64[0].into(), test_vm.regs.regs64[2].into()),
+            RegLocal::REG128 => (res, test_vm.regs.regs128[0], test_vm.regs.regs128[2]),
+        }
+    }
+
+    #[test]
+    fn test_div_negative_remainder() {
+        for bank in &[RegLocal::REG32, RegLocal::REG64, RegLocal::REG128] {
+            let (res, q, r) = run_div(*bank, Opcode::DIV, -7, 2, OverflowMode::Trapping);
+            assert!(res.is_ok());
+            assert_eq!((q, r), (-3, -1));
+
+            let (_, q, r) = run_div(*bank, Opcode::DIV, 7, -2, OverflowMode::Trapping);
+            assert_eq!((q, r), (-3, 1));
+
+            let (_, m, _) = run_div(*bank, Opcode::MOD, -7, 2, OverflowMode::Trapping);
+            assert_eq!(m, -1);
+        }
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        for bank in &[RegLocal::REG32, RegLocal::REG64, RegLocal::REG128] {
+            for op in &[Opcode::DIV, Opcode::MOD] {
+                let (res, q, _) = run_div(*bank, *op, 7, 0, OverflowMode::Wrapping);
+                assert_eq!(
+                    res,
+                    Err(VmError::DivideByZero {
+                        script: 0,
+                        pc: 2 * (2 + bank.size()),
+                        opcode: *op as u8
+                    })
+                );
+                assert_eq!(q, 7);
+            }
+        }
+    }
+
+    #[test]
+    fn test_div_min_by_minus_one() {
+        let mins = [
+            (RegLocal::REG32, i128::from(i32::MIN), i128::from(i32::MAX)),
+            (RegLocal::REG64, i128::from(i64::MIN), i128::from(i64::MAX)),
+            (RegLocal::REG128, i128::MIN, i128::MAX),
+        ];
+        for &(bank, min, max) in &mins {
+            let (res, q, r) = run_div(bank, Opcode::DIV, min, -1, OverflowMode::Wrapping);
+            assert!(res.is_ok());
+            assert_eq!((q, r), (min, 0));
+
+            let (_, q, r) = run_div(bank, Opcode::DIV, min, -1, OverflowMode::Saturating);
+            assert_eq!((q, r), (max, 0));
+
+            let (res, q, _) = run_div(bank, Opcode::DIV, min, -1, OverflowMode::Trapping);
+            assert!(matches!(res, Err(VmError::Overflow { .. })));
+            assert_eq!(q, min);
+
+            // MOD never overflows
+            let (res, m, _) = run_div(bank, Opcode::MOD, min, -1, OverflowMode::Trapping);
+            assert!(res.is_ok());
+            assert_eq!(m, 0);
+        }
+    }
+
+    #[test]
+    fn test_extend_and_truncate() {
+        let r32 = 0;
+        let r64 = 1 << 6;
+        let r128 = 2 << 6;
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                r32,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFE,
+                Opcode::SEXT as u8,
+                r64,
+                r32,
+                Opcode::ZEXT as u8,
+                r128,
+                r32,
+                Opcode::ZEXT as u8,
+                r128 + 1,
+                r64,
+                Opcode::MOV as u8,
+                r32 + 1,
+                r32,
+                Opcode::LOD as u8,
+                r64 + 1,
+                0x12,
+                0x34,
+                0x56,
+                0x78,
+                0x80,
+                0x00,
+                0x00,
+                0x01,
+                Opcode::TRUNC as u8,
+                r32 + 2,
+                r64 + 1,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[0], -2);
+        assert_eq!(test_vm.regs.regs128[0], 0xFFFF_FFFE);
+        assert_eq!(test_vm.regs.regs128[1], 0xFFFF_FFFF_FFFF_FFFE);
+        assert_eq!(test_vm.regs.regs32[1], -2);
+        assert_eq!(test_vm.regs.regs32[2], 0x8000_0001 as i32);
+    }
+
+    #[test]
+    fn test_conversion_gas() {
+        let (r32, r64, r128) = (0, 1 << 6, 2 << 6);
+        // Each costs one ALU op per word of its wider register
+        let cases = [
+            (Opcode::SEXT, r64, r32, 2),
+            (Opcode::SEXT, r128, r64, 4),
+            (Opcode::ZEXT, r128, r32, 4),
+            (Opcode::ZEXT, r64, r32, 2),
+            (Opcode::TRUNC, r32, r64, 2),
+            (Opcode::TRUNC, r32, r128, 4),
+            (Opcode::TRUNC, r64, r128, 4),
+        ];
+        for &(op, dst, src, gas) in cases.iter() {
+            let script = [Bytes::from(&[op as u8, dst, src, Opcode::HLT as u8][..])];
+            let mut heap = BytesMut::new();
+            let mut test_vm = VMScript::new(&script, &mut heap).unwrap();
+            assert_eq!(test_vm.run(u64::MAX), Ok(ExitStatus::Halted { gas_used: gas }), "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_width_mismatch() {
+        let r32 = 0;
+        let r64 = 1 << 6;
+        let cases = [
+            [Opcode::ADD as u8, r32, r64, 0],
+            [Opcode::CMP as u8, r64, r32, 0],
+            [Opcode::MOV as u8, r64, r32, 0],
+            [Opcode::SEXT as u8, r32, r64, 0],
+            [Opcode::ZEXT as u8, r64, r64 + 1, 0],
+            [Opcode::TRUNC as u8, r64, r32, 0],
+        ];
+        for case in &cases {
+            let script_arr = [Bytes::from(&case[..])];
+            let mut heap = BytesMut::with_capacity(0xFF);
+            let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+            assert_eq!(
+                test_vm.run(u64::MAX),
+                Err(VmError::WidthMismatch {
+                    script: 0,
+                    pc: 0,
+                    opcode: case[0],
+                    reg1: case[1],
+                    reg2: case[2]
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_immediates32() {
+        let script = Bytes::from(
+            &[
+                Opcode::LOD as u8,
+                0,
+                0,
+                0,
+                0,
+                100,
+                Opcode::ADDI as u8,
+                0,
+                0,
+                0,
+                0,
+                5,
+                Opcode::SUBI as u8,
+                0,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                Opcode::MULI as u8,
+                0,
+                0,
+                0,
+                0,
+                2,
+                Opcode::ANDI as u8,
+                0,
+                0,
+                0,
+                0,
+                0xFE,
+                Opcode::ORI as u8,
+                0,
+                0,
+                0,
+                0x01,
+                0,
+                Opcode::XORI as u8,
+                0,
+                0,
+                0,
+                0,
+                0x0F,
+                Opcode::CMPI as u8,
+                0,
+                0,
+                0,
+                0x01,
+                0xDB,
+                0,
+            ][..],
+        );
+        let script_arr = [script];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs32[0], ((((100 + 5 - -1) * 2) & 0xFE) | 0x100) ^ 0x0F);
+        assert!(test_vm.regs.f_eq);
+    }
+
+    #[test]
+    fn test_immediates_wide() {
+        let r64 = 1 << 6;
+        let r128 = 2 << 6;
+        let mut script = vec![Opcode::ADDI as u8, r64];
+        script.extend_from_slice(&(-3i64).to_be_bytes());
+        script.extend_from_slice(&[Opcode::ADDI as u8, r128]);
+        script.extend_from_slice(&(1i128 << 100).to_be_bytes());
+        script.extend_from_slice(&[Opcode::CMPI as u8, r128]);
+        script.extend_from_slice(&(1i128 << 101).to_be_bytes());
+        script.push(0);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        assert_eq!(test_vm.regs.regs64[0], -3);
+        assert_eq!(test_vm.regs.regs128[0], 1 << 100);
+        assert!(test_vm.regs.f_lt);
+    }
+
+    // Loads a into r0 and b into r1 of `bank`, runs `tail` and returns r0 and the CMP flags
+    fn run_pair(bank: RegLocal, a: i128, b: i128, tail: &[u8]) -> (i128, bool, bool, bool) {
+        let mut script = load_pair(bank, a, b);
+        script.extend_from_slice(tail);
+        let script_arr = [Bytes::from(script)];
+        let mut heap = BytesMut::with_capacity(0xFF);
+        let mut test_vm = VMScript::new(&script_arr, &mut heap).unwrap();
+        test_vm.run(u64::MAX).unwrap();
+        (test_vm.reg_value(bank, 0), test_vm.regs.f_eq, test_vm.regs.f_lt, test_vm.regs.f_gt)
+    }
+
+    // MAX and MIN of a bank, sign-extended to i128, and its width in bits
+    fn boundaries(bank: RegLocal) -> (i128, i128, i128) {
+        match bank {
+            RegLocal::REG32 => (i32::MAX.into(), i32::MIN.into(), 32),
+            RegLocal::REG64 => (i64::MAX.into(), i64::MIN.into(), 64),
+            RegLocal::REG128 => (i128::MAX, i128::MIN, 128),
+        }
+    }
+
+    #[test]
+    fn test_cmpu() {
+        for bank in &[RegLocal::REG32, RegLocal::REG64, RegLocal::REG128] {
+            let (max, min, _) = boundaries(*bank);
+            let top = bank_bits(*bank);
+            let cmpu = [Opcode::CMPU as u8, top, top + 1, 0];
+            let cmp = [Opcode::CMP as u8, top, top + 1, 0];
+            // (a, b, eq, lt, gt) with the unsigned answer
+            let cases = [
+                (min, max, false, false, true),
+                (-1, 0, false, false, true),
+                (0, -1, false, true, false),
+                (-1, -1, true, false, false),
+                (1, min, false, true, false),
+                (max, max, true, false, false),
+            ];
+            for &(a, b, eq, lt, gt) in &cases {
+                let (_, f_eq, f_lt, f_gt) = run_pair(*bank, a, b, &cmpu);
+                assert_eq!((f_eq, f_lt, f_gt), (eq, lt, gt));
+            }
+            // Signed CMP disagrees once the top bit is set
+            let (_, _, f_lt, _) = run_pair(*bank, min, max, &cmp);
+            assert!(f_lt);
+        }
     }
 
+    #[test]
+    fn test_divu_modu() {
+        for bank in &[RegLocal::REG32, RegLocal::REG64, RegLocal::REG128] {
+            let (max, min, _) = boundaries(*bank);
+            let top = bank_bits(*bank);
+            let divu = [Opcode::DIVU as u8, top, top + 1, Opcode::REM as u8, top + 2, 0];
+            let modu = [Opcode::MODU as u8, top, top + 1, 0];
+            let (q, _, _, _) = run_pair(*bank, -1, 2, &divu);
+            assert_eq!(q, max);
+            let (r, _, _, _) = run_pair(*bank, -1, 2, &modu);
+            assert_eq!(r, 1);
+            let (q, _, _, _) = run_pair(*bank, min, -1, &divu);
+            assert_eq!(q, 0);
+            let (r, _, _, _) = run_pair(*bank, min, -1, &modu);
+            assert_eq!(r, min);
+            let (q, _, _, _) = run_pair(*bank, -1, min, &divu);
+            assert_eq!(q, 1);
+            let (r, _, _, _) = run_pair(*bank, -1, min, &modu);
+            assert_eq!(r, max);
+            let (q, _, _, _) = run_pair(*bank, max, 1, &divu);
+            assert_eq!(q, max);
+        }
+    }
+
+    #[test]
+    fn test_divu_remainder_and_zero() {
+        let (res, q, r) = run_div(RegLocal::REG64, Opcode::DIVU, -1, 10, OverflowMode::Trapping);
+        assert!(res.is_ok());
+        assert_eq!(q, i128::from((u64::MAX / 10) as i64));
+        assert_eq!(r, 5);
+
+        let (res, _, _) = run_div(RegLocal::REG128, Opcode::MODU, -1, 0, OverflowMode::Wrapping);
+        assert!(matches!(res, Err(VmError::DivideByZero { .. })));
+    }
+
+    #[test]
+    fn test_shru() {
+        for bank in &[RegLocal::REG32, RegLocal::REG64, RegLocal::REG128] {
+            let (max, min, bits) = boundaries(*bank);
+            let top = bank_bits(*bank);
+            let (v, _, _, _) = run_pair(*bank, -1, 0, &[Opcode::SHRU as u8, top, 1, 0]);
+            assert_eq!(v, max);
+            let (v, _, _, _) = run_pair(*bank, min, 0, &[Opcode::SHRU as u8, top, (bits - 1) as u8, 0]);
+            assert_eq!(v, 1);
+            let (v, _, _, _) = run_pair(*bank, -1, 0, &[Opcode::SHRU as u8, top, bits as u8, 0]);
+            assert_eq!(v, 0);
+            let (v, _, _, _) = run_pair(*bank, -1, 0, &[Opcode::SHRU as u8, top, 0, 0]);
+            assert_eq!(v, -1);
+            // SHR still sign-extends
+            let (v, _, _, _) = run_pair(*bank, min, 0, &[Opcode::SHR as u8, top, (bits - 1) as u8, 0]);
+            assert_eq!(v, -1);
+        }
+    }
+
+    #[test]
+    fn test_reset_and_set_scripts() {
+        let scripts = [Bytes::from(&[Opcode::LOD as u8, 0, 0, 0, 0, 7, Opcode::PSH as u8, 0, Opcode::HLT as u8][..])];
+        let mut heap = BytesMut::new();
+        let mut test_vm = VMScript::new(&scripts, &mut heap).unwrap();
+        assert!(test_vm.run(u64::MAX).is_ok());
+        assert_eq!((test_vm.register(0), test_vm.heap().len()), (Some(7), 4));
+
+        // New scripts start from the top with the registers and heap left as they were
+        test_vm.set_scripts(Bytes::from(&[Opcode::PSH as u8, 0, Opcode::HLT as u8][..]), &[]);
+        assert_eq!((test_vm.script_index(), test_vm.pc()), (0, 0));
+        assert!(test_vm.run(u64::MAX).is_ok());
+        assert_eq!((test_vm.register(0), test_vm.heap().len()), (Some(7), 8));
+
+        test_vm.reset();
+        assert_eq!(test_vm.registers(), &Registers::default());
+        assert!(test_vm.heap().is_empty());
+        assert_eq!((test_vm.script_index(), test_vm.pc(), test_vm.gas_used()), (0, 0, 0));
+    }
 }